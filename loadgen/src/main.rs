@@ -0,0 +1,143 @@
+mod stats;
+mod workloads;
+
+use std::{env, error::Error, process, sync::Arc, time::Duration};
+
+use tokio::{sync::Barrier, task::JoinSet, time::Instant};
+
+use crate::stats::Stats;
+
+const PROBLEMS: [&str; 6] = [
+    p00_smoke_test::PROBLEM,
+    p01_prime_time::PROBLEM,
+    p02_means_to_an_end::PROBLEM,
+    p03_budget_chat::PROBLEM,
+    p04_unusual_database_program::PROBLEM,
+    p05_mob_in_the_middle::PROBLEM,
+];
+
+struct Options {
+    clients: usize,
+    duration: Duration,
+    /// Bytes per echo round trip.
+    size: usize,
+    /// Inserts between queries for means-to-an-end and unusual-database.
+    query_every: usize,
+    /// Chat messages per second per client.
+    rate: f64,
+}
+
+/// Shared by every client of a run.
+pub struct Run {
+    addr: String,
+    options: Options,
+    /// Chat messages carry their send time relative to this.
+    epoch: Instant,
+    deadline: Instant,
+    barrier: Barrier,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let (Some(problem), Some(addr)) = (args.next(), args.next()) else {
+        usage();
+    };
+    if !PROBLEMS.contains(&problem.as_str()) {
+        usage();
+    }
+    let Some(options) = parse_options(args) else {
+        usage();
+    };
+
+    println!(
+        "Running {} against {} with {} client(s) for {:?}",
+        problem, addr, options.clients, options.duration
+    );
+
+    let epoch = Instant::now();
+    let run = Arc::new(Run {
+        addr,
+        deadline: epoch + options.duration,
+        epoch,
+        barrier: Barrier::new(options.clients),
+        options,
+    });
+
+    let mut clients = JoinSet::new();
+    for id in 0..run.options.clients {
+        let run = run.clone();
+        let problem = problem.clone();
+        clients.spawn(async move {
+            let mut stats = Stats::default();
+            if let Err(e) = run_client(&problem, &run, id, &mut stats).await {
+                eprintln!("Client {} failed: {}", id, e);
+                stats.errors += 1;
+            }
+            stats
+        });
+    }
+
+    let mut total = Stats::default();
+    while let Some(stats) = clients.join_next().await {
+        total.merge(stats?);
+    }
+    total.print(run.epoch.elapsed().min(run.options.duration));
+
+    Ok(())
+}
+
+async fn run_client(problem: &str, run: &Run, id: usize, stats: &mut Stats) -> std::io::Result<()> {
+    match problem {
+        p00_smoke_test::PROBLEM => workloads::echo(run, id, stats).await,
+        p01_prime_time::PROBLEM => workloads::prime(run, id, stats).await,
+        p02_means_to_an_end::PROBLEM => workloads::means(run, id, stats).await,
+        // The proxy is loaded with chat traffic passing through it
+        p03_budget_chat::PROBLEM | p05_mob_in_the_middle::PROBLEM => {
+            workloads::chat(run, id, stats).await
+        }
+        p04_unusual_database_program::PROBLEM => workloads::kv(run, id, stats).await,
+        _ => unreachable!("checked against PROBLEMS"),
+    }
+}
+
+fn parse_options(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    let mut options = Options {
+        clients: 10,
+        duration: Duration::from_secs(10),
+        size: 4096,
+        query_every: 10,
+        rate: 10.0,
+    };
+
+    while let Some(flag) = args.next() {
+        let value = args.next()?;
+        match flag.as_str() {
+            "--clients" => options.clients = value.parse().ok().filter(|&n| n > 0)?,
+            "--duration" => {
+                options.duration = Duration::try_from_secs_f64(value.parse().ok()?).ok()?
+            }
+            "--size" => options.size = value.parse().ok().filter(|&n| n > 0)?,
+            "--query-every" => options.query_every = value.parse().ok().filter(|&n| n > 0)?,
+            // The chat workload sends one message per tick, which must be a real interval
+            "--rate" => {
+                options.rate = value.parse().ok().filter(|&r: &f64| {
+                    r > 0.0
+                        && r.is_finite()
+                        && Duration::try_from_secs_f64(1.0 / r).is_ok_and(|tick| !tick.is_zero())
+                })?
+            }
+            _ => return None,
+        }
+    }
+
+    Some(options)
+}
+
+fn usage() -> ! {
+    eprintln!("usage: loadgen <problem> <addr> [--clients n] [--duration secs]");
+    eprintln!("       [--size bytes] [--query-every n] [--rate msgs-per-sec]");
+    eprintln!();
+    eprintln!("problems: {}", PROBLEMS.join(", "));
+    process::exit(2);
+}
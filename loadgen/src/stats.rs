@@ -0,0 +1,61 @@
+use std::time::Duration;
+
+/// What one client achieved during a run.
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// Completed requests, or for chat workloads, delivered messages.
+    pub requests: u64,
+    pub bytes: u64,
+    pub errors: u64,
+    pub latencies: Vec<Duration>,
+}
+
+impl Stats {
+    /// Counts one completed request.
+    pub fn record(&mut self, latency: Duration, bytes: usize) {
+        self.requests += 1;
+        self.bytes += bytes as u64;
+        self.latencies.push(latency);
+    }
+
+    pub fn merge(&mut self, other: Stats) {
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+        self.errors += other.errors;
+        self.latencies.extend(other.latencies);
+    }
+
+    pub fn print(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        println!(
+            "Requests: {} ({:.1}/s)",
+            self.requests,
+            self.requests as f64 / secs
+        );
+        if self.bytes > 0 {
+            println!(
+                "Bytes: {} ({:.2} MiB/s)",
+                self.bytes,
+                self.bytes as f64 / secs / (1024.0 * 1024.0)
+            );
+        }
+        println!("Errors: {}", self.errors);
+
+        if self.latencies.is_empty() {
+            return;
+        }
+        self.latencies.sort_unstable();
+        let percentile = |p: f64| {
+            let rank = (p * self.latencies.len() as f64).ceil() as usize;
+            self.latencies[rank.clamp(1, self.latencies.len()) - 1]
+        };
+        println!(
+            "Latency: p50 {:?}, p90 {:?}, p99 {:?}, p99.9 {:?}, max {:?}",
+            percentile(0.5),
+            percentile(0.9),
+            percentile(0.99),
+            percentile(0.999),
+            self.latencies[self.latencies.len() - 1]
+        );
+    }
+}
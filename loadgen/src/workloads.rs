@@ -0,0 +1,235 @@
+//! One client of each problem's workload. Clients run until the deadline, adding what
+//! they complete to their [`Stats`]; an error ends the client early.
+
+use std::{io, time::Duration};
+
+use tokio::{
+    io::Lines,
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{
+        TcpStream, UdpSocket,
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+    },
+    time::{Instant, MissedTickBehavior, interval, timeout, timeout_at},
+};
+
+use crate::{Run, stats::Stats};
+
+// How long a UDP client waits for a reply before counting the request as lost
+const UDP_REPLY_TIMEOUT: Duration = Duration::from_secs(1);
+// Keys each UDP client cycles through
+const KV_KEYS: u64 = 1000;
+
+/// Writes `size` bytes and waits for them to come back. Reading starts alongside
+/// writing, since the server echoes large payloads before it has them all.
+pub async fn echo(run: &Run, id: usize, stats: &mut Stats) -> io::Result<()> {
+    let (mut reader, mut writer) = connect(run).await?.into_split();
+    let mut rng = Rng::new(id);
+    let sent: Vec<u8> = (0..run.options.size).map(|_| rng.next() as u8).collect();
+    let mut received = vec![0; sent.len()];
+
+    while Instant::now() < run.deadline {
+        let started = Instant::now();
+        let round_trip =
+            async { tokio::try_join!(writer.write_all(&sent), reader.read_exact(&mut received)) };
+        // A round trip cut off by the deadline isn't counted
+        let Ok(result) = timeout_at(run.deadline, round_trip).await else {
+            break;
+        };
+        result?;
+        if received != sent {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "echo mismatch"));
+        }
+        stats.record(started.elapsed(), sent.len() * 2);
+    }
+    Ok(())
+}
+
+/// One isPrime request at a time over random numbers.
+pub async fn prime(run: &Run, id: usize, stats: &mut Stats) -> io::Result<()> {
+    let stream = connect(run).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut rng = Rng::new(id);
+
+    while Instant::now() < run.deadline {
+        let request = format!(
+            "{{\"method\":\"isPrime\",\"number\":{}}}\n",
+            rng.next() % 1_000_000_007
+        );
+        let started = Instant::now();
+        writer.write_all(request.as_bytes()).await?;
+        let response = lines.next_line().await?.ok_or_else(closed)?;
+        if !response.contains("\"prime\"") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, response));
+        }
+        stats.record(started.elapsed(), request.len() + response.len() + 1);
+    }
+    Ok(())
+}
+
+/// Inserts prices at increasing timestamps, querying the whole range every
+/// `query_every` inserts. Only queries have a latency.
+pub async fn means(run: &Run, id: usize, stats: &mut Stats) -> io::Result<()> {
+    let mut stream = connect(run).await?;
+    let mut rng = Rng::new(id);
+    let mut timestamp = 0i32;
+    let mut mean = [0; 4];
+
+    while Instant::now() < run.deadline {
+        let mut batch = Vec::with_capacity(9 * run.options.query_every);
+        for _ in 0..run.options.query_every {
+            timestamp = timestamp.wrapping_add(1);
+            batch.push(b'I');
+            batch.extend_from_slice(&timestamp.to_be_bytes());
+            batch.extend_from_slice(&((rng.next() % 1000) as i32).to_be_bytes());
+        }
+        stream.write_all(&batch).await?;
+        stats.requests += run.options.query_every as u64;
+        stats.bytes += batch.len() as u64;
+
+        let mut query = vec![b'Q'];
+        query.extend_from_slice(&0i32.to_be_bytes());
+        query.extend_from_slice(&timestamp.to_be_bytes());
+        let started = Instant::now();
+        stream.write_all(&query).await?;
+        stream.read_exact(&mut mean).await?;
+        stats.record(started.elapsed(), query.len() + mean.len());
+    }
+    Ok(())
+}
+
+/// Joins the room, then sends `rate` messages a second stamped with their send time,
+/// measuring how long each takes to reach the other users.
+pub async fn chat(run: &Run, id: usize, stats: &mut Stats) -> io::Result<()> {
+    let joined = join_chat(run, id).await;
+    // Everyone joins before anyone talks, so fan-out is the same throughout
+    run.barrier.wait().await;
+    let (mut lines, mut writer) = joined?;
+
+    let send = async {
+        let mut ticks = interval(Duration::from_secs_f64(1.0 / run.options.rate));
+        ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticks.tick().await;
+            if Instant::now() >= run.deadline {
+                return Ok::<_, io::Error>(());
+            }
+            let sent_at = run.epoch.elapsed().as_micros();
+            writer
+                .write_all(format!("sent {}\n", sent_at).as_bytes())
+                .await?;
+        }
+    };
+
+    let receive = async {
+        let mut delivered = Stats::default();
+        while let Ok(line) = timeout_at(run.deadline, lines.next_line()).await {
+            let Some(line) = line? else {
+                return Err(closed());
+            };
+            // Skip presence notifications
+            let Some(sent_at) = line
+                .split_once("] sent ")
+                .and_then(|(_, micros)| micros.parse::<u64>().ok())
+            else {
+                continue;
+            };
+            let latency = run
+                .epoch
+                .elapsed()
+                .saturating_sub(Duration::from_micros(sent_at));
+            delivered.record(latency, line.len() + 1);
+        }
+        Ok(delivered)
+    };
+
+    let (sent, delivered) = tokio::join!(send, receive);
+    stats.merge(delivered?);
+    sent
+}
+
+async fn join_chat(
+    run: &Run,
+    id: usize,
+) -> io::Result<(Lines<BufReader<OwnedReadHalf>>, OwnedWriteHalf)> {
+    let stream = connect(run).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let connect = async {
+        lines.next_line().await?.ok_or_else(closed)?;
+        writer.write_all(format!("user{}\n", id).as_bytes()).await?;
+        lines.next_line().await?.ok_or_else(closed)?;
+        Ok::<_, io::Error>(())
+    };
+    timeout(Duration::from_secs(10), connect)
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "joining the chat timed out"))??;
+
+    Ok((lines, writer))
+}
+
+/// Inserts over UDP, retrieving a key every `query_every` inserts. Lost replies
+/// count as errors without ending the client.
+pub async fn kv(run: &Run, id: usize, stats: &mut Stats) -> io::Result<()> {
+    let target = tokio::net::lookup_host(&run.addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, run.addr.clone()))?;
+    let socket = UdpSocket::bind(if target.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    })
+    .await?;
+    socket.connect(target).await?;
+
+    let mut rng = Rng::new(id);
+    let mut reply = vec![0; 1024];
+
+    while Instant::now() < run.deadline {
+        for _ in 0..run.options.query_every {
+            let insert = format!("k{}-{}={}", id, rng.next() % KV_KEYS, rng.next());
+            socket.send(insert.as_bytes()).await?;
+            stats.requests += 1;
+            stats.bytes += insert.len() as u64;
+        }
+
+        let key = format!("k{}-{}", id, rng.next() % KV_KEYS);
+        let started = Instant::now();
+        socket.send(key.as_bytes()).await?;
+        match timeout(UDP_REPLY_TIMEOUT, socket.recv(&mut reply)).await {
+            Ok(received) => stats.record(started.elapsed(), key.len() + received?),
+            Err(_) => stats.errors += 1,
+        }
+    }
+    Ok(())
+}
+
+/// xorshift64, enough to vary requests without pulling in a crate.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: usize) -> Self {
+        Self(0x9e37_79b9_7f4a_7c15 ^ (seed as u64 + 1).wrapping_mul(0x2545_f491_4f6c_dd1d))
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+}
+
+/// Nagle's algorithm would add the client's own delays to the measured latency.
+async fn connect(run: &Run) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect(&run.addr).await?;
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+fn closed() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed")
+}
@@ -0,0 +1,28 @@
+use server::{ConnectionContext, HandlerError, Stream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+pub const PROBLEM: &str = "smoke-test";
+
+pub async fn echo_handler(mut stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+    let addr = ctx.peer();
+    server::log_info!(addr, "Echo handler started");
+    let mut buf = [0u8; 1024];
+
+    loop {
+        match stream.read(&mut buf).await {
+            Ok(0) => {
+                server::log_info!(addr, "Connection closed by client");
+                break;
+            }
+            Ok(n) => {
+                server::log_msg_in!(addr, format!("{} bytes", n));
+
+                stream.write_all(&buf[..n]).await?;
+                server::log_msg_out!(addr, format!("{} byte echoed", n));
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
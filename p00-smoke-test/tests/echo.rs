@@ -0,0 +1,24 @@
+use p00_smoke_test::echo_handler;
+use server::ServerBuilder;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+};
+
+#[tokio::test]
+async fn echoes_until_the_client_closes() {
+    let server = ServerBuilder::new("127.0.0.1:0")
+        .tcp(echo_handler)
+        .await
+        .unwrap();
+    let addr = server.local_addr().as_inet().unwrap();
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_all(b"hello, world").await.unwrap();
+    client.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    client.read_to_end(&mut echoed).await.unwrap();
+    assert_eq!(echoed, b"hello, world");
+
+    server.shutdown().await.unwrap();
+}
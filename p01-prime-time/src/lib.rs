@@ -0,0 +1,68 @@
+mod prime;
+mod protocol;
+
+use server::codec::{DelimiterCodec, SinkExt, StreamExt, framed};
+use server::{ConnectionContext, HandlerError, Stream};
+use std::time::Instant;
+
+use crate::prime::is_prime;
+use crate::protocol::*;
+
+pub const PROBLEM: &str = "prime-time";
+
+const MAX_LINE_LENGTH: usize = 1 << 20;
+const REQUESTS: &str = "prime_time_requests_total";
+
+pub async fn prime_handler(stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+    let (addr, metrics) = (ctx.peer(), ctx.metrics());
+    let mut lines = framed(stream, DelimiterCodec::lines(MAX_LINE_LENGTH), &ctx);
+
+    loop {
+        match lines.next().await {
+            None => {
+                server::log_info!(addr, "Connection closed by client");
+                break;
+            }
+            Some(Ok(line)) => {
+                let started = Instant::now();
+                let line = String::from_utf8_lossy(&line);
+                server::log_msg_in!(addr, line.trim());
+
+                let response = match parse_request(&line) {
+                    Ok(request) if request.is_valid() => {
+                        if let Some(number) = request.get_number() {
+                            metrics.increment(REQUESTS, "method", "isPrime");
+                            let prime_result = is_prime(number);
+                            server::log_info!(
+                                addr,
+                                format!("isPrime({}) = {}", number, prime_result)
+                            );
+                            serialize_response(&Response::new(prime_result))
+                                .map_err(HandlerError::internal)?
+                        } else {
+                            metrics.increment(REQUESTS, "method", "malformed");
+                            let response = serialize_response(&MalformedResponse::new())
+                                .map_err(HandlerError::internal)?;
+                            lines.send(response.as_bytes()).await?;
+                            return Err(HandlerError::protocol("invalid number in request"));
+                        }
+                    }
+                    _ => {
+                        metrics.increment(REQUESTS, "method", "malformed");
+                        let response = serialize_response(&MalformedResponse::new())
+                            .map_err(HandlerError::internal)?;
+                        lines.send(response.as_bytes()).await?;
+                        return Err(HandlerError::protocol("malformed request"));
+                    }
+                };
+
+                lines.send(response.as_bytes()).await?;
+                metrics.request_completed(started.elapsed());
+                server::log_msg_out!(addr, response);
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
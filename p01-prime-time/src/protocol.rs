@@ -51,7 +51,5 @@ pub fn parse_request(line: &str) -> Result<Request, serde_json::Error> {
 }
 
 pub fn serialize_response<T: Serialize>(response: &T) -> Result<String, serde_json::Error> {
-    let mut json = serde_json::to_string(response)?;
-    json.push('\n');
-    Ok(json)
+    serde_json::to_string(response)
 }
@@ -0,0 +1,59 @@
+mod protocol;
+mod session;
+
+use server::codec::{FixedSizeCodec, StreamExt, framed_read};
+use server::{ConnectionContext, HandlerError, Stream};
+use std::time::Instant;
+use tokio::io::AsyncWriteExt;
+
+use protocol::{MESSAGE_SIZE, Message, serialize_mean};
+
+use crate::session::Session;
+
+pub const PROBLEM: &str = "means-to-an-end";
+
+const MESSAGES: &str = "means_to_an_end_messages_total";
+
+pub async fn query_handler(stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+    let (addr, metrics) = (ctx.peer(), ctx.metrics());
+    let (reader, mut writer) = stream.into_split();
+    let mut messages = framed_read(reader, FixedSizeCodec::new(MESSAGE_SIZE), &ctx);
+
+    let mut session = Session::new();
+
+    loop {
+        match messages.next().await {
+            None => {
+                server::log_info!(addr, "Connection closed by client");
+                break;
+            }
+            Some(Ok(buf)) => {
+                server::log_msg_in!(addr, format!("Received {} bytes", buf.len()));
+
+                match Message::parse(&buf) {
+                    Some(Message::Query { mintime, maxtime }) => {
+                        metrics.increment(MESSAGES, "type", "query");
+                        let started = Instant::now();
+                        let mean = session.query(mintime, maxtime);
+                        let response = serialize_mean(mean);
+                        writer.write_all(&response).await?;
+                        metrics.request_completed(started.elapsed());
+                    }
+                    Some(Message::Insert { timestamp, price }) => {
+                        metrics.increment(MESSAGES, "type", "insert");
+                        session.insert(timestamp, price);
+                    }
+                    _ => {
+                        metrics.increment(MESSAGES, "type", "invalid");
+                        let response = "unrecognized request, disconnecting";
+                        writer.write_all(response.as_bytes()).await?;
+                        return Err(HandlerError::protocol("unrecognized message type"));
+                    }
+                }
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
@@ -1,3 +1,5 @@
+pub const MESSAGE_SIZE: usize = 9;
+
 pub enum Message {
     Insert { timestamp: i32, price: i32 },
     Query { mintime: i32, maxtime: i32 },
@@ -5,7 +7,7 @@ pub enum Message {
 
 impl Message {
     pub fn parse(buf: &[u8]) -> Option<Self> {
-        if buf.len() != 9 {
+        if buf.len() != MESSAGE_SIZE {
             return None;
         }
 
@@ -1,10 +1,10 @@
+use server::queue::QueueSender;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::sync::Mutex;
-use tokio::sync::mpsc::UnboundedSender;
 
 pub struct ChatRoom {
-    users: Mutex<HashMap<String, UnboundedSender<String>>>,
+    users: Mutex<HashMap<String, QueueSender<String>>>,
 }
 
 impl ChatRoom {
@@ -14,7 +14,7 @@ impl ChatRoom {
         })
     }
 
-    pub async fn join(&self, name: String, tx: UnboundedSender<String>) {
+    pub async fn join(&self, name: String, tx: QueueSender<String>) {
         self.users.lock().await.insert(name, tx);
     }
 
@@ -26,7 +26,7 @@ impl ChatRoom {
         let users = self.users.lock().await;
         for (user, tx) in users.iter() {
             if Some(user.as_str()) != except {
-                let _ = tx.send(msg.to_string());
+                tx.send(msg.to_string());
             }
         }
     }
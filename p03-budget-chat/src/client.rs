@@ -1,110 +1,122 @@
 use crate::chat::ChatRoom;
 use crate::protocol::{format_join_message, format_leave_message, format_user_list, is_valid_name};
-use server::Metrics;
-use std::{net::SocketAddr, sync::Arc};
-use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
-use tokio::sync::mpsc::unbounded_channel;
+use server::codec::{DelimiterCodec, StreamExt, framed_read};
+use server::queue::{QueueLimits, queue};
+use server::{ConnectionContext, HandlerError, Stream};
+use std::{sync::Arc, time::Instant};
+use tokio::io::AsyncWriteExt;
+
+const MAX_LINE_LENGTH: usize = 64 * 1024;
+const EVENTS: &str = "budget_chat_events_total";
 
 pub async fn handle_client(
-    stream: TcpStream,
+    stream: Stream,
     chat_room: Arc<ChatRoom>,
-    addr: SocketAddr,
-    metrics: Metrics,
-) {
-    let (tx, mut rx) = unbounded_channel();
+    ctx: ConnectionContext,
+    welcome: &str,
+    queue_limits: QueueLimits,
+) -> Result<(), HandlerError> {
+    let (addr, metrics) = (ctx.peer(), ctx.metrics());
+    // Slow readers are held to the configured backlog rather than buffering every
+    // message sent to the room
+    let (tx, mut rx) = queue(queue_limits, metrics);
     let (reader, mut writer) = stream.into_split();
-    let mut reader = BufReader::new(reader);
-    let mut message_buffer = Vec::<u8>::new();
+    let mut lines = framed_read(reader, DelimiterCodec::lines(MAX_LINE_LENGTH), &ctx);
 
-    let welcome_msg = b"Welcome to Nyx 3.0! What shall I call you?\n";
-    if writer.write_all(welcome_msg).await.is_err() {
-        server::log_error!(addr, "Failed to send welcome message");
-        return;
-    }
-    metrics.bytes_sent(welcome_msg.len() as u64);
+    let welcome_msg = format!("{}\n", welcome);
+    writer.write_all(welcome_msg.as_bytes()).await?;
     server::log_msg_out!(addr, "Welcome message sent");
 
-    let mut name = String::new();
-    match reader.read_line(&mut name).await {
-        Ok(0) => {
+    let name = match lines.next().await {
+        None => {
             server::log_info!(addr, "Client disconnected during handshake");
-            return;
+            return Ok(());
         }
-        Ok(n) => {
-            metrics.bytes_received(n as u64);
-            server::log_msg_in!(addr, format!("Name: {}", name.trim()));
+        Some(Ok(line)) => {
+            let name = String::from_utf8_lossy(&line).trim().to_string();
+            server::log_msg_in!(addr, format!("Name: {}", name));
+            name
         }
-        Err(e) => {
-            server::log_error!(addr, format!("Failed to read name: {}", e));
-            metrics.error_occurred();
-            return;
-        }
-    }
-
-    let name = name.trim().to_string();
+        Some(Err(e)) => return Err(e.into()),
+    };
 
     if !is_valid_name(&name) {
-        server::log_warning!(addr, format!("Invalid name rejected: {}", name));
+        metrics.increment(EVENTS, "event", "rejected");
         let _ = writer.write_all(b"Invalid name. Disconnecting\n").await;
-        return;
+        return Err(HandlerError::protocol(format!("invalid name {:?}", name)));
     }
 
     // Send presence notification and user list
     server::log_info!(addr, format!("User '{}' joined", name));
+    metrics.increment(EVENTS, "event", "join");
     chat_room.join(name.clone(), tx).await;
     let join_notif = format_join_message(&name);
     chat_room.broadcast(&join_notif, Some(&name)).await;
     let list_notif = format_user_list(&chat_room.user_list(Some(&name)).await);
     let _ = writer.write_all(list_notif.as_bytes()).await;
 
-    // Main message loop
-    loop {
-        let mut buffer = [0u8; 1024];
+    // Main message loop, ending with the error that closed the session if any
+    let result = loop {
         tokio::select! {
-            result = reader.read(&mut buffer) => {
+            result = lines.next() => {
                 match result {
-                    Ok(0) => {
+                    None => {
                         server::log_info!(addr, format!("User '{}' disconnected", name));
-                        break;
+                        break Ok(());
                     }
-                    Ok(n) => {
-                        metrics.bytes_received(n as u64);
-                        message_buffer.extend_from_slice(&buffer[..n]);
+                    Some(Ok(line)) => {
+                        let line = String::from_utf8_lossy(&line);
+                        let msg = line.trim();
 
-                        while let Some(newline_pos) = message_buffer.iter().position(|&b| b == b'\n') {
-                            let line_bytes = message_buffer.drain(..=newline_pos).collect::<Vec<u8>>();
-                            let line = String::from_utf8_lossy(&line_bytes[..line_bytes.len() - 1]);
-                            let msg = line.trim();
-
-                            server::log_msg_in!(addr, format!("[{}]: {}", name, msg));
-                            let formatted_msg = format!("[{}] {}\n", name, msg);
-                            chat_room.broadcast(&formatted_msg, Some(&name)).await;
-                        }
-                    }
-                    Err(e) => {
-                        server::log_error!(addr, format!("Read error for '{}': {}", name, e));
-                        metrics.error_occurred();
-                        break;
+                        server::log_msg_in!(addr, format!("[{}]: {}", name, msg));
+                        metrics.increment(EVENTS, "event", "message");
+                        let started = Instant::now();
+                        let formatted_msg = format!("[{}] {}\n", name, msg);
+                        chat_room.broadcast(&formatted_msg, Some(&name)).await;
+                        metrics.request_completed(started.elapsed());
                     }
+                    Some(Err(e)) => break Err(e.into()),
                 }
             }
             // Receive from chat room
-            Some(msg) = rx.recv() => {
+            received = rx.recv() => {
+                let msg = match received {
+                    Ok(Some(msg)) => msg,
+                    // The room holds our sender until we leave
+                    Ok(None) => break Ok(()),
+                    // Fell too far behind under the `disconnect` overflow policy
+                    Err(e) => break Err(e.into()),
+                };
                 if let Err(e) = writer.write_all(msg.as_bytes()).await {
-                    server::log_error!(addr, format!("Write error for '{}': {}", name, e));
-                    metrics.error_occurred();
-                    break;
+                    break Err(e.into());
                 }
-                metrics.bytes_sent(msg.len() as u64);
                 server::log_msg_out!(addr, msg.trim());
             }
+            // Shutting down: deliver messages already queued for this user first. A
+            // kicked client's stream no longer works, so there is nothing to deliver.
+            _ = ctx.cancelled() => {
+                if ctx.is_kicked() {
+                    server::log_info!(addr, format!("User '{}' was kicked", name));
+                    break Ok(());
+                }
+                server::log_info!(addr, format!("Closing, flushing messages for '{}'", name));
+                while let Some(msg) = rx.try_recv() {
+                    if writer.write_all(msg.as_bytes()).await.is_err() {
+                        break;
+                    }
+                    server::log_msg_out!(addr, msg.trim());
+                }
+                break Ok(());
+            }
         }
-    }
+    };
 
     // Remove user from chat room
     chat_room.leave(&name).await;
+    metrics.increment(EVENTS, "event", "leave");
     let leave_notif = format_leave_message(&name);
     chat_room.broadcast(&leave_notif, Some(&name)).await;
     server::log_info!(addr, format!("User '{}' has left the chat", name));
+
+    result
 }
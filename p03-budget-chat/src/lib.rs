@@ -0,0 +1,49 @@
+mod chat;
+mod client;
+mod protocol;
+
+use server::{ConnectionContext, HandlerError, QueueLimits, ServerConfig, Stream, TcpHandler};
+use std::sync::Arc;
+
+use crate::chat::ChatRoom;
+
+pub const PROBLEM: &str = "budget-chat";
+/// Settings read by [`ChatServer::from_config`].
+pub const OPTIONS: &[&str] = &["welcome"];
+
+const DEFAULT_WELCOME: &str = "Welcome to Nyx 3.0! What shall I call you?";
+
+/// One chat room shared by every connection.
+pub struct ChatServer {
+    room: Arc<ChatRoom>,
+    welcome: String,
+    queue_limits: QueueLimits,
+}
+
+impl ChatServer {
+    /// Reads the greeting from the `welcome` option and bounds each user's backlog of
+    /// undelivered messages by `outbound_queue`.
+    pub fn from_config(config: &ServerConfig) -> Self {
+        Self {
+            room: ChatRoom::new(),
+            welcome: config.option_or("welcome", DEFAULT_WELCOME).to_string(),
+            queue_limits: config.outbound_queue,
+        }
+    }
+}
+
+impl TcpHandler for ChatServer {
+    async fn handle(&self, stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+        server::log_info!(ctx.peer(), "Chat client connected");
+
+        // Delegate to client handler
+        client::handle_client(
+            stream,
+            self.room.clone(),
+            ctx,
+            &self.welcome,
+            self.queue_limits,
+        )
+        .await
+    }
+}
@@ -8,15 +8,11 @@ pub struct KVStore {
 }
 
 impl KVStore {
-    pub async fn new() -> Self {
-        let db = Arc::new(Mutex::new(HashMap::<Vec<u8>, Vec<u8>>::new()));
-
-        {
-            let mut db_guard = db.lock().await;
-            db_guard.insert(b"version".to_vec(), b"KVStore 2.0".to_vec());
+    pub fn new() -> Self {
+        let db = HashMap::from([(b"version".to_vec(), b"KVStore 2.0".to_vec())]);
+        Self {
+            db: Arc::new(Mutex::new(db)),
         }
-
-        Self { db }
     }
 
     pub async fn insert(&self, key: &[u8], value: &[u8]) -> Result<(), Box<dyn Error>> {
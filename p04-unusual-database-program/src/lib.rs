@@ -0,0 +1,103 @@
+mod db;
+mod protocol;
+
+use server::{ConnectionContext, Datagram, DatagramSocket, HandlerError, UdpHandler};
+
+use crate::db::KVStore;
+use crate::protocol::{ProtocolError, Request, format_response, parse_request};
+
+pub const PROBLEM: &str = "unusual-database";
+
+const REQUESTS: &str = "unusual_database_requests_total";
+
+/// Serves requests against a single store shared by all clients.
+pub struct KVServer {
+    db: KVStore,
+}
+
+impl KVServer {
+    pub fn new() -> Self {
+        Self { db: KVStore::new() }
+    }
+}
+
+impl Default for KVServer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl UdpHandler for KVServer {
+    async fn handle(
+        &self,
+        packet: Datagram,
+        socket: DatagramSocket,
+        ctx: ConnectionContext,
+    ) -> Result<(), HandlerError> {
+        handle_request(&self.db, packet, socket, ctx).await
+    }
+}
+
+async fn handle_request(
+    db: &KVStore,
+    packet: Datagram,
+    socket: DatagramSocket,
+    ctx: ConnectionContext,
+) -> Result<(), HandlerError> {
+    let (client_addr, metrics) = (ctx.peer(), ctx.metrics());
+    // Only the start of an oversize datagram arrives, which may still parse
+    let request = if packet.is_truncated() {
+        Err(ProtocolError::TooLong)
+    } else {
+        parse_request(&packet)
+    };
+
+    match request {
+        Ok(Request::Insert { key, value }) => {
+            server::log_msg_in!(
+                client_addr,
+                format!(
+                    "INSERT: {} = {:?}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                )
+            );
+            metrics.increment(REQUESTS, "type", "insert");
+
+            if key == b"version" {
+                server::log_warning!(client_addr, "Attempt to modify version key ignored");
+            } else {
+                let _ = db.insert(&key, &value).await;
+                server::log_info!(client_addr, "Insert completed");
+            }
+        }
+
+        Ok(Request::Retrieve { key }) => {
+            server::log_msg_in!(
+                client_addr,
+                format!("RETRIEVE: {}", String::from_utf8_lossy(&key))
+            );
+            metrics.increment(REQUESTS, "type", "retrieve");
+
+            if let Some(value) = db.get(&key).await {
+                let response = format_response(&key, &value);
+
+                socket.reply(&response).await?;
+
+                server::log_msg_in!(client_addr, format!("Response: {} bytes", response.len()));
+            } else {
+                let response = format_response(&key, &[]);
+                socket.reply(&response).await?;
+
+                server::log_msg_out!(client_addr, "Key not found, sent empty response");
+            }
+        }
+
+        Err(e) => {
+            metrics.increment(REQUESTS, "type", "invalid");
+            return Err(HandlerError::protocol(format!("{:?}", e)));
+        }
+    }
+
+    Ok(())
+}
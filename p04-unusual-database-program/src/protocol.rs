@@ -19,7 +19,7 @@ pub fn parse_request(packet: &[u8]) -> Result<Request, ProtocolError> {
     if let Some(eq_pos) = packet.iter().position(|&b| b == b'=') {
         let key = &packet[..eq_pos];
         let value = &packet[eq_pos + 1..];
-        if key.iter().any(|&b| b == b'=') {
+        if key.contains(&b'=') {
             return Err(ProtocolError::InvalidKey);
         }
 
@@ -0,0 +1,41 @@
+use p04_unusual_database_program::KVServer;
+use server::ServerBuilder;
+use std::time::Duration;
+use tokio::{net::UdpSocket, time::timeout};
+
+async fn request(client: &UdpSocket, request: &[u8]) -> Vec<u8> {
+    client.send(request).await.unwrap();
+    let mut buf = [0; 1000];
+    let len = timeout(Duration::from_secs(5), client.recv(&mut buf))
+        .await
+        .unwrap()
+        .unwrap();
+    buf[..len].to_vec()
+}
+
+#[tokio::test]
+async fn inserts_and_retrieves() {
+    let server = ServerBuilder::new("127.0.0.1:0")
+        .udp(KVServer::new())
+        .await
+        .unwrap();
+    let addr = server.local_addr().as_inet().unwrap();
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client.connect(addr).await.unwrap();
+    // Datagrams are handled concurrently, so the insert may land after the next request
+    client.send(b"foo=bar=baz").await.unwrap();
+    let mut reply = request(&client, b"foo").await;
+    for _ in 0..100 {
+        if reply != b"foo=" {
+            break;
+        }
+        reply = request(&client, b"foo").await;
+    }
+    assert_eq!(reply, b"foo=bar=baz");
+    assert_eq!(request(&client, b"missing").await, b"missing=");
+    client.send(b"version=hacked").await.unwrap();
+    assert_eq!(request(&client, b"version").await, b"version=KVStore 2.0");
+
+    server.shutdown().await.unwrap();
+}
@@ -0,0 +1,43 @@
+mod proxy;
+mod rewrite;
+
+use std::time::Duration;
+
+use server::{ConnectionContext, HandlerError, ServerConfig, Stream, TcpHandler};
+
+use crate::proxy::{DEFAULT_CONNECT_TIMEOUT, DEFAULT_UPSTREAM_ADDR, handle_client};
+
+pub const PROBLEM: &str = "mob-in-the-middle";
+/// Settings read by [`Proxy::from_config`].
+pub const OPTIONS: &[&str] = &["upstream"];
+
+pub struct Proxy {
+    upstream_addr: String,
+    connect_timeout: Duration,
+}
+
+impl Proxy {
+    /// Reads the upstream chat server from the `upstream` option. Connecting to it
+    /// gets as long as a client gets to start talking.
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let timeouts = config.timeouts;
+        Self {
+            upstream_addr: config
+                .option_or("upstream", DEFAULT_UPSTREAM_ADDR)
+                .to_string(),
+            connect_timeout: timeouts
+                .handshake
+                .or(timeouts.idle)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+        }
+    }
+}
+
+impl TcpHandler for Proxy {
+    async fn handle(&self, stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+        server::log_info!(ctx.peer(), "Proxy connection opened");
+        handle_client(stream, &ctx, &self.upstream_addr, self.connect_timeout).await?;
+        server::log_info!(ctx.peer(), "Proxy connection closed");
+        Ok(())
+    }
+}
@@ -1,31 +1,9 @@
-mod proxy;
-mod rewrite;
+use std::error::Error;
 
-use server::{Metrics, run_tcp};
-use std::{error::Error, net::SocketAddr};
-use tokio::net::TcpStream;
+use p05_mob_in_the_middle::{OPTIONS, PROBLEM, Proxy};
+use server::{ServerConfig, runtime};
 
-use crate::proxy::handle_client;
-
-async fn middle_handler(
-    stream: TcpStream,
-    addr: SocketAddr,
-    metrics: Metrics,
-) -> Result<(), Box<dyn Error>> {
-    server::log_info!(addr, "Proxy connection opened");
-    let result = handle_client(stream, addr).await;
-
-    if let Err(ref e) = result {
-        metrics.error_occurred();
-        server::log_error!(addr, "Proxy handler error: {}", e);
-    } else {
-        server::log_info!(addr, "Proxy connection closed");
-    }
-
-    result
+fn main() -> Result<(), Box<dyn Error>> {
+    let config = ServerConfig::load(PROBLEM, OPTIONS)?;
+    runtime::run_tcp(&config, Proxy::from_config(&config))
 }
-
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    run_tcp("0.0.0.0:8000", middle_handler).await
-}
\ No newline at end of file
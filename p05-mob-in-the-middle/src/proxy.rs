@@ -1,51 +1,83 @@
-use tokio::net::TcpStream;
-use tokio::io::{AsyncWriteExt, BufReader, AsyncBufReadExt};
-use std::error::Error;
-use std::net::SocketAddr;
+use std::{io, time::Duration};
+
+use server::codec::{
+    DelimiterCodec, FramedRead, FramedWrite, SinkExt, StreamExt, framed_read, framed_write,
+};
+use server::{ConnectionContext, HandlerError, Stream};
+use tokio::{net::TcpStream, time::timeout};
 
 use crate::rewrite::rewrite_boguscoin;
 
-const UPSTREAM_ADDR: &str = "chat.protohackers.com:16963";
+pub const DEFAULT_UPSTREAM_ADDR: &str = "chat.protohackers.com:16963";
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_LINE_LENGTH: usize = 64 * 1024;
 
-pub async fn handle_client(client: TcpStream, addr: SocketAddr) -> Result<(), Box<dyn Error>> {
-    let upstream =  TcpStream::connect(UPSTREAM_ADDR).await?;
+pub async fn handle_client(
+    client: Stream,
+    ctx: &ConnectionContext,
+    upstream_addr: &str,
+    connect_timeout: Duration,
+) -> Result<(), HandlerError> {
+    let addr = ctx.peer().clone();
+    let upstream = timeout(connect_timeout, TcpStream::connect(upstream_addr))
+        .await
+        .unwrap_or_else(|_| {
+            Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "timed out connecting upstream",
+            ))
+        })
+        .map_err(HandlerError::Upstream)?;
 
-    let (client_reader, mut client_writer) = client.into_split();
-    let mut client_reader = BufReader::new(client_reader);
-    let (upstream_reader, mut upstream_writer) = upstream.into_split();
-    let mut upstream_reader = BufReader::new(upstream_reader);
+    // Only the client side is metered, upstream traffic isn't ours
+    let (client_reader, client_writer) = client.into_split();
+    let mut client_reader = framed_read(client_reader, DelimiterCodec::lines(MAX_LINE_LENGTH), ctx);
+    let mut client_writer =
+        framed_write(client_writer, DelimiterCodec::lines(MAX_LINE_LENGTH), ctx);
+    let (upstream_reader, upstream_writer) = upstream.into_split();
+    let mut upstream_reader =
+        FramedRead::new(upstream_reader, DelimiterCodec::lines(MAX_LINE_LENGTH));
+    let mut upstream_writer =
+        FramedWrite::new(upstream_writer, DelimiterCodec::lines(MAX_LINE_LENGTH));
 
     // Client -> Upstream
-    let c2u = tokio::spawn(async move {
-        let mut line = Vec::new();
-        loop {
-            line.clear();
-            let n = client_reader.read_until(b'\n', &mut line).await?;
-            if n == 0 { break; }
+    let c2u = async {
+        while let Some(line) = client_reader.next().await {
+            let line = line?;
             let rewritten = rewrite_boguscoin(&line);
-            upstream_writer.write_all(&rewritten).await?;
+            upstream_writer
+                .send(rewritten.as_slice())
+                .await
+                .map_err(HandlerError::Upstream)?;
             server::log_msg_in!(addr, format!("{}", String::from_utf8_lossy(&line)));
-            server::log_msg_in!(addr, format!("(REWRITTEN) {} ", String::from_utf8_lossy(&rewritten)));
+            server::log_msg_in!(
+                addr,
+                format!("(REWRITTEN) {} ", String::from_utf8_lossy(&rewritten))
+            );
         }
-        Ok::<_, std::io::Error>(())
-    });
+        Ok::<_, HandlerError>(())
+    };
 
     // Upstream -> Client
-    let u2c = tokio::spawn(async move {
-        let mut line = Vec::new();
-        loop {
-            line.clear();
-            let n = upstream_reader.read_until(b'\n', &mut line).await?;
-            if n == 0 { break; }
+    let u2c = async {
+        while let Some(line) = upstream_reader.next().await {
+            let line = line.map_err(HandlerError::Upstream)?;
             let rewritten = rewrite_boguscoin(&line);
-            client_writer.write_all(&rewritten).await?;
+            client_writer.send(rewritten.as_slice()).await?;
             server::log_msg_out!(addr, format!("{}", String::from_utf8_lossy(&line)));
-            server::log_msg_out!(addr, format!("(REWRITTEN) {} ", String::from_utf8_lossy(&rewritten)));
+            server::log_msg_out!(
+                addr,
+                format!("(REWRITTEN) {} ", String::from_utf8_lossy(&rewritten))
+            );
         }
-        Ok::<_, std::io::Error>(())
-    });
-
-    let _ = tokio::try_join!(c2u, u2c);
+        // Upstream hung up, so the client is disconnected too
+        client_writer.close().await?;
+        Ok::<_, HandlerError>(())
+    };
 
-    Ok(())
-}
\ No newline at end of file
+    // Either side closing ends the session; both sockets are dropped with it
+    tokio::select! {
+        result = c2u => result,
+        result = u2c => result,
+    }
+}
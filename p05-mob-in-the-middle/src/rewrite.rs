@@ -24,4 +24,4 @@ pub fn rewrite_boguscoin(input: &[u8]) -> Vec<u8> {
             }
         })
         .to_vec()
-}
\ No newline at end of file
+}
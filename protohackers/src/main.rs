@@ -0,0 +1,225 @@
+use std::{env, error::Error, io, net::SocketAddr, process};
+
+use server::{
+    ConfigError, Connections, Metrics, ServerBuilder, ServerConfig, ServerHandle, Shutdown,
+    TlsFiles,
+    activation::FD_PREFIX,
+    listener::{UNIX_PREFIX, split_addrs},
+    runtime,
+};
+use tokio::task::JoinSet;
+
+// In port order for `all`
+const PROBLEMS: [&str; 6] = [
+    p00_smoke_test::PROBLEM,
+    p01_prime_time::PROBLEM,
+    p02_means_to_an_end::PROBLEM,
+    p03_budget_chat::PROBLEM,
+    p04_unusual_database_program::PROBLEM,
+    p05_mob_in_the_middle::PROBLEM,
+];
+
+// Keys only some problems read; `all` passes its arguments to every problem
+const OPTIONS: [&[&str]; 2] = [p03_budget_chat::OPTIONS, p05_mob_in_the_middle::OPTIONS];
+
+const ALL: &str = "all";
+const GEN_CERT: &str = "gen-cert";
+
+fn main() -> Result<(), Box<dyn Error>> {
+    let mut args = env::args().skip(1);
+    let command = args.next().unwrap_or_default();
+    let args: Vec<String> = args.collect();
+
+    match command.as_str() {
+        ALL => run_all(args),
+        GEN_CERT if args.len() >= 2 => gen_cert(&args),
+        problem if PROBLEMS.contains(&problem) => run_one(problem, args),
+        _ => {
+            eprintln!("usage: protohackers <problem> [--key value]...");
+            eprintln!("       protohackers gen-cert <cert.pem> <key.pem> [name]...");
+            eprintln!();
+            eprintln!("problems: {}, {}", PROBLEMS.join(", "), ALL);
+            process::exit(2);
+        }
+    }
+}
+
+fn run_one(problem: &str, args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let options = match problem {
+        p03_budget_chat::PROBLEM => p03_budget_chat::OPTIONS,
+        p05_mob_in_the_middle::PROBLEM => p05_mob_in_the_middle::OPTIONS,
+        _ => &[],
+    };
+    let config = ServerConfig::from_sources(problem, options, args, env::vars())?;
+    match problem {
+        p00_smoke_test::PROBLEM => runtime::run_tcp(&config, p00_smoke_test::echo_handler),
+        p01_prime_time::PROBLEM => runtime::run_tcp(&config, p01_prime_time::prime_handler),
+        p02_means_to_an_end::PROBLEM => {
+            runtime::run_tcp(&config, p02_means_to_an_end::query_handler)
+        }
+        p03_budget_chat::PROBLEM => {
+            runtime::run_tcp(&config, p03_budget_chat::ChatServer::from_config(&config))
+        }
+        p04_unusual_database_program::PROBLEM => {
+            runtime::run_udp(&config, p04_unusual_database_program::KVServer::new())
+        }
+        p05_mob_in_the_middle::PROBLEM => {
+            runtime::run_tcp(&config, p05_mob_in_the_middle::Proxy::from_config(&config))
+        }
+        _ => unreachable!("unknown problem {}", problem),
+    }
+}
+
+/// Runs every problem on consecutive ports from `addr`, counting into one set of metrics.
+fn run_all(args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    let base = ServerConfig::from_sources(ALL, &OPTIONS.concat(), args.clone(), env::vars())?;
+    // Shards would each need every problem's listeners
+    if base.runtime.per_core {
+        return Err(ConfigError::InvalidValue {
+            key: "per_core_listeners".to_string(),
+            value: format!("not supported with {}", ALL),
+        }
+        .into());
+    }
+    base.runtime.build()?.block_on(serve_all(base, args))
+}
+
+async fn serve_all(base: ServerConfig, args: Vec<String>) -> Result<(), Box<dyn Error>> {
+    base.apply_logging();
+
+    let shutdown = Shutdown::on_signals();
+    let metrics = Metrics::new();
+    let connections = Connections::new();
+
+    let mut servers = JoinSet::new();
+    for (offset, problem) in PROBLEMS.into_iter().enumerate() {
+        let mut config =
+            ServerConfig::from_sources(problem, &OPTIONS.concat(), args.clone(), env::vars())?;
+        let addrs = offset_addrs(&base.addr, offset, problem)?;
+        config.addr = if problem == p04_unusual_database_program::PROBLEM {
+            // UDP listens on one socket, the first IP address or passed-in socket given
+            addrs
+                .into_iter()
+                .find(|addr| !addr.starts_with(UNIX_PREFIX))
+                .ok_or_else(|| ConfigError::InvalidValue {
+                    key: "addr".to_string(),
+                    value: format!("{} has no IP address for {}", base.addr, problem),
+                })?
+        } else {
+            addrs.join(",")
+        };
+        // One exporter and admin listener are enough, metrics and connections are shared
+        config.metrics_addr = base.metrics_addr.clone().filter(|_| offset == 0);
+        config.admin_addr = base.admin_addr.clone().filter(|_| offset == 0);
+
+        let builder = ServerBuilder::from_config(&config)
+            .shutdown(shutdown.clone())
+            .metrics(metrics.clone())
+            .connections(connections.clone());
+
+        let server = start(problem, &config, builder).await?;
+        server::log_info!(server.local_addr(), format!("Serving {}", problem));
+        servers.spawn(server.wait());
+    }
+
+    let stats = server::spawn_stats(metrics.clone(), Some(base.stats_interval));
+
+    let mut result = Ok(());
+    while let Some(joined) = servers.join_next().await {
+        if let Err(e) = joined.map_err(io::Error::other).and_then(|r| r) {
+            // Take the remaining servers down with the failed one
+            shutdown.trigger();
+            result = Err(e.into());
+        }
+    }
+
+    if let Some(stats) = stats {
+        stats.abort();
+    }
+    metrics.print_stats();
+    result
+}
+
+/// Writes a self-signed certificate for local TLS testing, valid for `localhost` by default.
+fn gen_cert(args: &[String]) -> Result<(), Box<dyn Error>> {
+    let files = TlsFiles::new(&args[0], &args[1]);
+    let mut names: Vec<&str> = args[2..].iter().map(String::as_str).collect();
+    if names.is_empty() {
+        names = vec!["localhost", "127.0.0.1"];
+    }
+
+    server::tls::generate_self_signed(&names, &files)?;
+    println!(
+        "Wrote {} and {} for {}",
+        files.cert.display(),
+        files.key.display(),
+        names.join(", ")
+    );
+    Ok(())
+}
+
+async fn start(
+    problem: &str,
+    config: &ServerConfig,
+    builder: ServerBuilder,
+) -> io::Result<ServerHandle> {
+    match problem {
+        p00_smoke_test::PROBLEM => builder.tcp(p00_smoke_test::echo_handler).await,
+        p01_prime_time::PROBLEM => builder.tcp(p01_prime_time::prime_handler).await,
+        p02_means_to_an_end::PROBLEM => builder.tcp(p02_means_to_an_end::query_handler).await,
+        p03_budget_chat::PROBLEM => {
+            builder
+                .tcp(p03_budget_chat::ChatServer::from_config(config))
+                .await
+        }
+        p04_unusual_database_program::PROBLEM => {
+            builder
+                .udp(p04_unusual_database_program::KVServer::new())
+                .await
+        }
+        p05_mob_in_the_middle::PROBLEM => {
+            builder
+                .tcp(p05_mob_in_the_middle::Proxy::from_config(config))
+                .await
+        }
+        _ => unreachable!("unknown problem {}", problem),
+    }
+}
+
+/// Moves every IP address in `base` up by `offset` ports and suffixes every Unix
+/// socket path with the problem's name.
+fn offset_addrs(base: &str, offset: usize, problem: &str) -> Result<Vec<String>, ConfigError> {
+    split_addrs(base)
+        .map(|addr| {
+            // Paths and socket names can't be offset, so each problem gets its own
+            if addr.starts_with(UNIX_PREFIX) || addr.starts_with(FD_PREFIX) {
+                return Ok(format!("{}.{}", addr, problem));
+            }
+            let addr: SocketAddr = addr.parse().map_err(|_| ConfigError::InvalidValue {
+                key: "addr".to_string(),
+                value: addr.to_string(),
+            })?;
+            offset_addr(addr, offset).map(|addr| addr.to_string())
+        })
+        .collect()
+}
+
+fn offset_addr(base: SocketAddr, offset: usize) -> Result<SocketAddr, ConfigError> {
+    // Port 0 stays 0 so every server gets its own ephemeral port
+    if base.port() == 0 {
+        return Ok(base);
+    }
+
+    let port = u16::try_from(offset)
+        .ok()
+        .and_then(|offset| base.port().checked_add(offset))
+        .ok_or_else(|| ConfigError::InvalidValue {
+            key: "addr".to_string(),
+            value: format!(
+                "{} has no room for {} consecutive ports",
+                base,
+                PROBLEMS.len()
+            ),
+        })?;
+    Ok(SocketAddr::new(base.ip(), port))
+}
@@ -0,0 +1,270 @@
+mod transcript;
+
+use std::{env, error::Error, fmt, io, path::PathBuf, process, time::Duration};
+
+use server::capture::Transport;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::{Instant, sleep_until, timeout_at},
+};
+
+use crate::transcript::{Action, Session, Step};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest excerpt of expected and received bytes shown in a report
+const EXCERPT_LEN: usize = 120;
+
+struct Options {
+    addr: String,
+    paths: Vec<PathBuf>,
+    fast: bool,
+    timeout: Duration,
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    Udp(UdpSocket),
+}
+
+/// The first point where the server didn't do what the transcript recorded.
+struct Divergence {
+    origin: String,
+    connection: String,
+    reason: String,
+    expected: Vec<u8>,
+    received: Vec<u8>,
+    // Offset of the first differing byte, if the bytes themselves differ
+    offset: Option<usize>,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let Some(options) = parse_args(env::args().skip(1)) else {
+        eprintln!("usage: replay [--fast] [--timeout <secs>] <addr> <transcript>...");
+        process::exit(2);
+    };
+
+    let session = Session::load(&options.paths)?;
+    match replay(&session, &options).await? {
+        None => println!(
+            "Replayed {} step(s) over {} connection(s) to {}, no divergence",
+            session.steps.len(),
+            session.connections.len(),
+            options.addr
+        ),
+        Some(divergence) => {
+            println!("{}", divergence);
+            process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Option<Options> {
+    let mut fast = false;
+    let mut timeout = DEFAULT_TIMEOUT;
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--fast" => fast = true,
+            "--timeout" => {
+                let secs = args.next()?.parse::<f64>().ok()?;
+                timeout = Duration::try_from_secs_f64(secs).ok()?;
+            }
+            _ if arg.starts_with("--") => return None,
+            _ => positional.push(arg),
+        }
+    }
+
+    if positional.len() < 2 {
+        return None;
+    }
+    let addr = positional.remove(0);
+    Some(Options {
+        addr,
+        paths: positional.into_iter().map(PathBuf::from).collect(),
+        fast,
+        timeout,
+    })
+}
+
+/// Runs the steps one at a time in transcript order, so responses that depend on
+/// other connections (e.g. chat broadcasts) are checked at the right point.
+async fn replay(session: &Session, options: &Options) -> io::Result<Option<Divergence>> {
+    let mut connections: Vec<Option<Connection>> =
+        session.connections.iter().map(|_| None).collect();
+    let start = Instant::now();
+
+    for step in &session.steps {
+        if !options.fast {
+            sleep_until(start + step.time).await;
+        }
+
+        if let Action::Close = step.action {
+            connections[step.connection] = None;
+            continue;
+        }
+
+        let connection = match &mut connections[step.connection] {
+            Some(connection) => connection,
+            slot => slot.insert(Connection::open(session.transport, &options.addr).await?),
+        };
+
+        let divergence = match &step.action {
+            Action::Send(data) => match connection.send(data).await {
+                Ok(()) => None,
+                Err(e) => Some(Divergence::new(
+                    session,
+                    step,
+                    format!("send failed: {}", e),
+                    &[],
+                    Vec::new(),
+                )),
+            },
+            Action::Expect(expected) => {
+                let deadline = Instant::now() + options.timeout;
+                let (received, reason) = connection.receive(expected.len(), deadline).await;
+                match reason {
+                    Some(reason) => {
+                        Some(Divergence::new(session, step, reason, expected, received))
+                    }
+                    None if received != *expected => Some(Divergence::new(
+                        session,
+                        step,
+                        "response differs".to_string(),
+                        expected,
+                        received,
+                    )),
+                    None => None,
+                }
+            }
+            Action::Close => None,
+        };
+
+        if divergence.is_some() {
+            return Ok(divergence);
+        }
+    }
+
+    Ok(None)
+}
+
+impl Connection {
+    async fn open(transport: Transport, addr: &str) -> io::Result<Self> {
+        match transport {
+            Transport::Tcp => Ok(Self::Tcp(TcpStream::connect(addr).await?)),
+            Transport::Udp => {
+                let target = tokio::net::lookup_host(addr)
+                    .await?
+                    .next()
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, addr.to_string()))?;
+                let local = if target.is_ipv4() {
+                    "0.0.0.0:0"
+                } else {
+                    "[::]:0"
+                };
+                let socket = UdpSocket::bind(local).await?;
+                socket.connect(target).await?;
+                Ok(Self::Udp(socket))
+            }
+        }
+    }
+
+    async fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.write_all(data).await,
+            Self::Udp(socket) => socket.send(data).await.map(|_| ()),
+        }
+    }
+
+    /// Reads `len` bytes over TCP, or one datagram over UDP. Returns what arrived and,
+    /// if that fell short, why.
+    async fn receive(&mut self, len: usize, deadline: Instant) -> (Vec<u8>, Option<String>) {
+        match self {
+            Self::Tcp(stream) => {
+                let mut received = Vec::with_capacity(len);
+                while received.len() < len {
+                    let mut buf = vec![0; len - received.len()];
+                    match timeout_at(deadline, stream.read(&mut buf)).await {
+                        Ok(Ok(0)) => return (received, Some("connection closed".to_string())),
+                        Ok(Ok(n)) => received.extend_from_slice(&buf[..n]),
+                        Ok(Err(e)) => return (received, Some(format!("read failed: {}", e))),
+                        Err(_) => return (received, Some("timed out".to_string())),
+                    }
+                }
+                (received, None)
+            }
+            Self::Udp(socket) => {
+                let mut buf = vec![0; 64 * 1024];
+                match timeout_at(deadline, socket.recv(&mut buf)).await {
+                    Ok(Ok(n)) => {
+                        buf.truncate(n);
+                        (buf, None)
+                    }
+                    Ok(Err(e)) => (Vec::new(), Some(format!("receive failed: {}", e))),
+                    Err(_) => (Vec::new(), Some("timed out".to_string())),
+                }
+            }
+        }
+    }
+}
+
+impl Divergence {
+    fn new(
+        session: &Session,
+        step: &Step,
+        reason: String,
+        expected: &[u8],
+        received: Vec<u8>,
+    ) -> Self {
+        let offset = expected
+            .iter()
+            .zip(&received)
+            .position(|(a, b)| a != b)
+            .or_else(|| {
+                (!received.is_empty() && received.len() != expected.len())
+                    .then(|| expected.len().min(received.len()))
+            });
+
+        Self {
+            origin: step.origin.clone(),
+            connection: session.connections[step.connection].clone(),
+            reason,
+            expected: expected.to_vec(),
+            received,
+            offset,
+        }
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "First divergence at {} (connection {}): {}",
+            self.origin, self.connection, self.reason
+        )?;
+
+        // Show both sides from just before the first differing byte
+        let start = self.offset.unwrap_or(0).saturating_sub(EXCERPT_LEN / 4);
+        if let Some(offset) = self.offset {
+            writeln!(f, "  differs at byte {}", offset)?;
+        }
+        writeln!(f, "  expected: {}", excerpt(&self.expected, start))?;
+        write!(f, "  received: {}", excerpt(&self.received, start))
+    }
+}
+
+fn excerpt(bytes: &[u8], start: usize) -> String {
+    let start = start.min(bytes.len());
+    let end = bytes.len().min(start + EXCERPT_LEN);
+    format!(
+        "{}\"{}\"{}",
+        if start > 0 { "..." } else { "" },
+        bytes[start..end].escape_ascii(),
+        if end < bytes.len() { "..." } else { "" }
+    )
+}
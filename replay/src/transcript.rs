@@ -0,0 +1,367 @@
+//! Sessions to replay, loaded from capture files or hand-written text transcripts.
+//!
+//! Text transcripts follow the example sessions in `data/*.md`, one step per line:
+//!
+//! ```text
+//! # comment
+//! transport udp                 (optional, before any steps; TCP by default)
+//! <-- alice                     client sends a line ("\n" appended over TCP)
+//! --> * The room contains:      server is expected to send a line
+//! <== 49 00 00 30 39            client sends raw bytes, given in hex
+//! ==> 00 00 00 65  # 101        expected raw bytes; a `#` starts a comment
+//! close                         client hangs up
+//! ```
+//!
+//! Text lines understand `\n`, `\r`, `\t`, `\\` and `\xNN` escapes. A step may start with
+//! `@<seconds>` to give its time from the start of the session, and a client name to run
+//! several connections at once, e.g. `@1.5 bob <-- hi`. Steps without a time happen
+//! at the previous step's time.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use server::capture::{Direction, Transcript, Transport};
+
+const DEFAULT_CLIENT: &str = "client";
+
+pub struct Session {
+    pub transport: Transport,
+    /// Name of each connection, indexed by [`Step::connection`].
+    pub connections: Vec<String>,
+    /// Every step across all connections, in time order.
+    pub steps: Vec<Step>,
+}
+
+pub struct Step {
+    pub connection: usize,
+    pub time: Duration,
+    pub action: Action,
+    /// Where the step came from, for reporting.
+    pub origin: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Send(Vec<u8>),
+    Expect(Vec<u8>),
+    Close,
+}
+
+impl Session {
+    /// Loads and merges the given files. Captures keep their relative timing, so
+    /// connections recorded by one server run replay in their original order.
+    pub fn load(paths: &[PathBuf]) -> io::Result<Self> {
+        let mut session = Self {
+            transport: Transport::Tcp,
+            connections: Vec::new(),
+            steps: Vec::new(),
+        };
+        let mut transports = Vec::new();
+        let mut captures = Vec::new();
+
+        for path in paths {
+            let bytes = fs::read(path)?;
+            let origin = path.display().to_string();
+            if Transcript::is_capture(&bytes) {
+                // A truncated capture is reported as such rather than read as text
+                let transcript = Transcript::from_reader(bytes.as_slice())
+                    .map_err(|e| invalid(&origin, &e.to_string()))?;
+                captures.push((path, transcript));
+            } else {
+                let text = String::from_utf8(bytes)
+                    .map_err(|_| invalid(&origin, "neither a capture file nor text"))?;
+                transports.push(session.parse_text(path, &text)?);
+            }
+        }
+
+        let base = captures
+            .iter()
+            .filter_map(|(_, transcript)| transcript.records.first())
+            .map(|record| record.time)
+            .min();
+        for (path, transcript) in captures {
+            let transport = transcript.transport;
+            transports.push(transport);
+            let connection = session.add_connection(transcript.peer_addr);
+            for (i, record) in transcript.records.into_iter().enumerate() {
+                let time = base
+                    .and_then(|base| record.time.duration_since(base).ok())
+                    .unwrap_or_default();
+                let action = match record.direction {
+                    // Only TCP records end of stream this way, an empty datagram is data
+                    Direction::Received
+                        if record.data.is_empty() && transport == Transport::Tcp =>
+                    {
+                        Action::Close
+                    }
+                    Direction::Received => Action::Send(record.data),
+                    Direction::Sent => Action::Expect(record.data),
+                };
+                session.steps.push(Step {
+                    connection,
+                    time,
+                    action,
+                    origin: format!("{} record {}", path.display(), i + 1),
+                });
+            }
+        }
+
+        if transports.windows(2).any(|pair| pair[0] != pair[1]) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "transcripts mix TCP and UDP",
+            ));
+        }
+        session.transport = transports.first().copied().unwrap_or(Transport::Tcp);
+
+        // Stable, so steps at the same time keep their file order
+        session.steps.sort_by_key(|step| step.time);
+        Ok(session)
+    }
+
+    fn add_connection(&mut self, name: String) -> usize {
+        self.connections.push(name);
+        self.connections.len() - 1
+    }
+
+    fn parse_text(&mut self, path: &Path, text: &str) -> io::Result<Transport> {
+        let mut transport = Transport::Tcp;
+        let mut clients: HashMap<String, usize> = HashMap::new();
+        let mut time = Duration::ZERO;
+
+        for (i, line) in text.lines().enumerate() {
+            let origin = format!("{}:{}", path.display(), i + 1);
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if let Some(name) = line.strip_prefix("transport ") {
+                transport = match name.trim() {
+                    "tcp" => Transport::Tcp,
+                    "udp" => Transport::Udp,
+                    _ => return Err(invalid(&origin, "unknown transport")),
+                };
+                continue;
+            }
+
+            let mut rest = line;
+            if let Some(stamp) = rest.strip_prefix('@') {
+                let (secs, tail) = split_token(stamp);
+                time = secs
+                    .parse::<f64>()
+                    .ok()
+                    .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                    .ok_or_else(|| invalid(&origin, "invalid time"))?;
+                rest = tail;
+            }
+
+            let (mut token, mut payload) = split_token(rest);
+            let mut client = DEFAULT_CLIENT;
+            if !matches!(token, "<--" | "-->" | "<==" | "==>" | "close") {
+                client = token;
+                (token, payload) = split_token(payload);
+            }
+
+            let action = match token {
+                "<--" => Action::Send(text_payload(&origin, payload, transport)?),
+                "-->" => Action::Expect(text_payload(&origin, payload, transport)?),
+                "<==" => Action::Send(hex_payload(&origin, payload)?),
+                "==>" => Action::Expect(hex_payload(&origin, payload)?),
+                "close" => Action::Close,
+                _ => return Err(invalid(&origin, "expected <--, -->, <==, ==> or close")),
+            };
+
+            let connection = match clients.get(client) {
+                Some(&connection) => connection,
+                None => {
+                    let connection = self.add_connection(client.to_string());
+                    clients.insert(client.to_string(), connection);
+                    connection
+                }
+            };
+            self.steps.push(Step {
+                connection,
+                time,
+                action,
+                origin,
+            });
+        }
+
+        Ok(transport)
+    }
+}
+
+/// Splits off the first whitespace-separated token and the single space after it.
+fn split_token(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    match s.char_indices().find(|(_, c)| c.is_whitespace()) {
+        Some((i, c)) => (&s[..i], &s[i + c.len_utf8()..]),
+        None => (s, ""),
+    }
+}
+
+fn text_payload(origin: &str, text: &str, transport: Transport) -> io::Result<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(text.len() + 1);
+    let mut chars = text.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| invalid(origin, "invalid \\x escape"))?;
+                bytes.push(byte);
+            }
+            _ => return Err(invalid(origin, "invalid escape")),
+        }
+    }
+
+    if transport == Transport::Tcp {
+        bytes.push(b'\n');
+    }
+    Ok(bytes)
+}
+
+fn hex_payload(origin: &str, text: &str) -> io::Result<Vec<u8>> {
+    let hex = text.split_once('#').map_or(text, |(hex, _)| hex);
+    let bytes = hex
+        .split_whitespace()
+        .map(|token| {
+            // from_str_radix alone would also take "+f"
+            (token.len() == 2 && token.bytes().all(|b| b.is_ascii_hexdigit()))
+                .then(|| u8::from_str_radix(token, 16).ok())
+                .flatten()
+                .ok_or_else(|| invalid(origin, &format!("invalid hex byte '{}'", token)))
+        })
+        .collect::<io::Result<Vec<u8>>>()?;
+
+    if bytes.is_empty() {
+        return Err(invalid(origin, "expected hex bytes"));
+    }
+    Ok(bytes)
+}
+
+fn invalid(origin: &str, msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", origin, msg))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> io::Result<Session> {
+        let mut session = Session {
+            transport: Transport::Tcp,
+            connections: Vec::new(),
+            steps: Vec::new(),
+        };
+        session.transport = session.parse_text(Path::new("t"), text)?;
+        Ok(session)
+    }
+
+    fn actions(session: &Session) -> Vec<&Action> {
+        session.steps.iter().map(|step| &step.action).collect()
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("{:?} parsed", text),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn directions() {
+        let session = parse(
+            "# comment\n\
+             <-- hi\\tthere\\x21\n\
+             --> * hello\n\
+             <== 49 00 00 30 39\n\
+             ==> 00 00 00 65  # 101\n\
+             close\n",
+        )
+        .unwrap();
+        assert_eq!(session.transport, Transport::Tcp);
+        assert_eq!(session.connections, [DEFAULT_CLIENT]);
+        assert_eq!(
+            actions(&session),
+            [
+                &Action::Send(b"hi\tthere!\n".to_vec()),
+                &Action::Expect(b"* hello\n".to_vec()),
+                &Action::Send(vec![0x49, 0, 0, 0x30, 0x39]),
+                &Action::Expect(vec![0, 0, 0, 0x65]),
+                &Action::Close,
+            ]
+        );
+        assert_eq!(session.steps[1].origin, "t:3");
+    }
+
+    #[test]
+    fn udp_lines_have_no_newline() {
+        let session = parse("transport udp\n<-- a=1\n").unwrap();
+        assert_eq!(session.transport, Transport::Udp);
+        assert_eq!(actions(&session), [&Action::Send(b"a=1".to_vec())]);
+    }
+
+    #[test]
+    fn times_and_clients() {
+        let session = parse(
+            "@0.5 alice <-- alice\n\
+             bob <-- bob\n\
+             @1.5 alice --> hi\n\
+             close\n",
+        )
+        .unwrap();
+        assert_eq!(session.connections, ["alice", "bob", DEFAULT_CLIENT]);
+        let steps: Vec<_> = session
+            .steps
+            .iter()
+            .map(|step| (step.connection, step.time.as_millis()))
+            .collect();
+        assert_eq!(steps, [(0, 500), (1, 500), (0, 1500), (2, 1500)]);
+    }
+
+    #[test]
+    fn malformed_lines() {
+        assert!(error("transport sctp\n").ends_with("unknown transport"));
+        assert!(error("@soon <-- hi\n").ends_with("invalid time"));
+        assert!(error("@-1 <-- hi\n").ends_with("invalid time"));
+        assert!(error("alice says hi\n").contains("expected <--"));
+        assert!(error("<-- \\q\n").ends_with("invalid escape"));
+        assert!(error("<-- \\xzz\n").ends_with("invalid \\x escape"));
+        assert_eq!(error("<-- ok\n==>\n"), "t:2: expected hex bytes");
+        assert_eq!(error("==> # nothing\n"), "t:1: expected hex bytes");
+    }
+
+    #[test]
+    fn strict_hex() {
+        for (line, token) in [
+            ("==> 00 0g", "0g"),
+            ("==> 00 101", "101"),
+            ("==> 0 01", "0"),
+            ("==> +f", "+f"),
+            ("==> 00 00 00 65  101 ab", "101"),
+        ] {
+            assert_eq!(
+                error(line),
+                format!("t:1: invalid hex byte '{}'", token),
+                "{}",
+                line
+            );
+        }
+    }
+}
@@ -0,0 +1,302 @@
+//! Sockets that are inherited rather than bound: from systemd socket activation, or
+//! from the previous server process during a zero-downtime upgrade.
+//!
+//! systemd passes sockets as descriptors from 3 up, announced in `LISTEN_FDS` and
+//! optionally named in `LISTEN_FDNAMES`; listen on one with `fd:<number>` or
+//! `fd:<name>`. [`spawn_replacement`] starts this program again and passes it every
+//! socket the servers here listen on, keyed by the address they were given. A server in
+//! the new process listening on the same address takes the socket over instead of
+//! binding, so no connection is refused while the old process drains. The new process
+//! reports back once it has taken every socket over; until then the old one keeps
+//! serving, and carries on if the new one fails.
+
+use socket2::{SockRef, Type};
+use std::{
+    collections::BTreeMap,
+    env,
+    io::{self, Write},
+    os::{
+        fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    process::{self, Child, Command},
+    sync::{LazyLock, Mutex},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, UdpSocket},
+    sync::watch,
+    time::timeout,
+};
+
+use crate::{listener, log_error};
+
+/// Listen addresses starting with this name a socket passed by systemd.
+pub const FD_PREFIX: &str = "fd:";
+
+const HANDOVER_VAR: &str = "SERVER_HANDOVER_FDS";
+// Descriptor the replacement reports readiness on, by writing a byte and closing it
+const READY_VAR: &str = "SERVER_HANDOVER_READY";
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+const SYSTEMD_VARS: [&str; 3] = ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"];
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+struct Inherited {
+    keys: Vec<String>,
+    fd: OwnedFd,
+}
+
+#[derive(Default)]
+struct Offered {
+    next_id: u64,
+    sockets: BTreeMap<u64, (String, OwnedFd)>,
+}
+
+static INHERITED: LazyLock<Mutex<Vec<Inherited>>> = LazyLock::new(|| Mutex::new(from_env()));
+static OFFERED: LazyLock<Mutex<Offered>> = LazyLock::new(Default::default);
+static READY: LazyLock<Mutex<Option<OwnedFd>>> = LazyLock::new(|| {
+    let fd = env::var(READY_VAR).ok().and_then(|fd| fd.parse().ok());
+    Mutex::new(fd.map(adopt))
+});
+static REPLACEMENT: Mutex<Option<Replacement>> = Mutex::new(None);
+
+/// A replacement process that has been started, and whether it has taken over yet.
+struct Replacement {
+    pid: u32,
+    ready: watch::Receiver<Option<bool>>,
+}
+
+/// Keeps a socket available to a replacement process until dropped.
+pub(crate) struct Offer(u64);
+
+/// Takes the socket inherited for `addr`, if any. `fd:` addresses must have one.
+pub(crate) fn take(addr: &str) -> io::Result<Option<OwnedFd>> {
+    let mut inherited = INHERITED.lock().unwrap();
+    let taken = match inherited
+        .iter()
+        .position(|socket| socket.keys.iter().any(|key| key == addr))
+    {
+        Some(index) => Ok(Some(inherited.remove(index).fd)),
+        None if addr.starts_with(FD_PREFIX) => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no socket {} was passed in", addr),
+        )),
+        None => Ok(None),
+    };
+    if inherited.is_empty() {
+        report_ready();
+    }
+    taken
+}
+
+/// Tells the process being replaced that every socket it passed has been taken over.
+fn report_ready() {
+    if let Some(fd) = READY.lock().unwrap().take() {
+        let _ = UnixStream::from(fd).write_all(b"1");
+    }
+}
+
+/// Offers a listening socket, bound to `addr`, to any replacement process.
+pub(crate) fn offer(addr: &str, socket: &impl AsFd) -> io::Result<Offer> {
+    let fd = socket.as_fd().try_clone_to_owned()?;
+    let mut offered = OFFERED.lock().unwrap();
+    let id = offered.next_id;
+    offered.next_id += 1;
+    offered.sockets.insert(id, (addr.to_string(), fd));
+    Ok(Offer(id))
+}
+
+impl Drop for Offer {
+    fn drop(&mut self) {
+        OFFERED.lock().unwrap().sockets.remove(&self.0);
+    }
+}
+
+/// Binds a TCP listener that isn't a server's own, such as the exporter's, or takes it
+/// over from the previous process.
+pub(crate) async fn tcp_listener(addr: &str) -> io::Result<(TcpListener, Offer)> {
+    let listener = match take(addr)? {
+        Some(fd) => {
+            check_type(&fd, Type::STREAM)?;
+            TcpListener::from_std(std::net::TcpListener::from(fd))?
+        }
+        None => TcpListener::bind(addr).await?,
+    };
+    let offer = offer(addr, &listener)?;
+    Ok((listener, offer))
+}
+
+/// Binds a UDP socket, or takes it over from systemd or the previous process.
+pub(crate) async fn udp_socket(addr: &str, reuse_port: bool) -> io::Result<(UdpSocket, Offer)> {
+    let socket = match take(addr)? {
+        Some(fd) => {
+            check_type(&fd, Type::DGRAM)?;
+            UdpSocket::from_std(std::net::UdpSocket::from(fd))?
+        }
+        None => listener::bind_udp(addr, reuse_port).await?,
+    };
+    let offer = offer(addr, &socket)?;
+    Ok((socket, offer))
+}
+
+/// Checks an inherited socket is of the expected type and readies it for tokio.
+pub(crate) fn check_type(fd: &OwnedFd, expected: Type) -> io::Result<()> {
+    let socket = SockRef::from(fd);
+    if socket.r#type()? != expected {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "inherited socket is of the wrong type",
+        ));
+    }
+    socket.set_nonblocking(true)
+}
+
+/// Starts this program again with the same arguments, passing it every offered
+/// socket, and waits until it has taken them all over. Returns the new process's ID;
+/// calls made meanwhile or later wait for the same process rather than starting
+/// another. The caller should then shut its servers down.
+///
+/// A replacement that exits first, or isn't ready within 30 seconds, is killed and
+/// reported as an error, leaving this process serving; a later call tries again.
+pub async fn spawn_replacement() -> io::Result<u32> {
+    let (pid, mut ready) = {
+        let mut replacement = REPLACEMENT.lock().unwrap();
+        if replacement.is_none() {
+            *replacement = Some(start_replacement()?);
+        }
+        let replacement = replacement.as_ref().unwrap();
+        (replacement.pid, replacement.ready.clone())
+    };
+
+    match ready.wait_for(Option::is_some).await.map(|ready| *ready) {
+        Ok(Some(true)) => Ok(pid),
+        _ => Err(io::Error::other(format!(
+            "replacement process {} failed to take over",
+            pid
+        ))),
+    }
+}
+
+fn start_replacement() -> io::Result<Replacement> {
+    // Only these duplicates lack close-on-exec, so they are all the child inherits
+    let offered = OFFERED.lock().unwrap();
+    let mut passed = Vec::new();
+    let mut spec = Vec::new();
+    for (addr, fd) in offered.sockets.values() {
+        let fd = fd.try_clone()?;
+        SockRef::from(&fd).set_cloexec(false)?;
+        spec.push(format!("{}={}", addr, fd.as_raw_fd()));
+        passed.push(fd);
+    }
+
+    let (ready, ready_child) = UnixStream::pair()?;
+    SockRef::from(&ready_child).set_cloexec(false)?;
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .env(HANDOVER_VAR, spec.join(","))
+        .env(READY_VAR, ready_child.as_raw_fd().to_string());
+    for var in SYSTEMD_VARS {
+        command.env_remove(var);
+    }
+    let child = command.spawn()?;
+    // Only the child's copy may remain, so its exit shows up as end of stream here
+    drop(ready_child);
+    drop(passed);
+
+    let pid = child.id();
+    let (report, ready_rx) = watch::channel(None);
+    ready.set_nonblocking(true)?;
+    let ready = tokio::net::UnixStream::from_std(ready)?;
+    tokio::spawn(await_ready(child, ready, report));
+    Ok(Replacement {
+        pid,
+        ready: ready_rx,
+    })
+}
+
+async fn await_ready(
+    mut child: Child,
+    mut ready: tokio::net::UnixStream,
+    report: watch::Sender<Option<bool>>,
+) {
+    let mut byte = [0];
+    let taken_over = matches!(
+        timeout(READY_TIMEOUT, ready.read(&mut byte)).await,
+        Ok(Ok(1))
+    );
+    if !taken_over {
+        log_error!(
+            "upgrade",
+            format!(
+                "Replacement process {} never took over, killing it",
+                child.id()
+            )
+        );
+        *REPLACEMENT.lock().unwrap() = None;
+        let _ = child.kill();
+        tokio::task::spawn_blocking(move || child.wait());
+    }
+    report.send_replace(Some(taken_over));
+}
+
+/// Whether a replacement has taken over this process's sockets.
+pub(crate) fn is_replaced() -> bool {
+    REPLACEMENT
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|replacement| *replacement.ready.borrow() == Some(true))
+}
+
+fn from_env() -> Vec<Inherited> {
+    let mut inherited = Vec::new();
+
+    // systemd's variables are inherited by our own children too, hence the PID check
+    let for_us =
+        env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok()) == Some(process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok());
+    if for_us && let Some(count) = count {
+        let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+        let mut names = names.split(':');
+        for fd in SD_LISTEN_FDS_START..SD_LISTEN_FDS_START.saturating_add(count) {
+            let mut keys = vec![format!("{}{}", FD_PREFIX, fd)];
+            if let Some(name) = names.next().filter(|name| !name.is_empty()) {
+                keys.push(format!("{}{}", FD_PREFIX, name));
+            }
+            inherited.push(Inherited {
+                keys,
+                fd: adopt(fd),
+            });
+        }
+    }
+
+    if let Ok(spec) = env::var(HANDOVER_VAR) {
+        for entry in spec.split(',') {
+            // Addresses may contain '=' themselves, descriptor numbers can't
+            if let Some((addr, fd)) = entry.rsplit_once('=')
+                && let Ok(fd) = fd.parse()
+            {
+                inherited.push(Inherited {
+                    keys: vec![addr.to_string()],
+                    fd: adopt(fd),
+                });
+            }
+        }
+    }
+
+    inherited
+}
+
+fn adopt(fd: RawFd) -> OwnedFd {
+    // SAFETY: the parent passed this descriptor for us to own, and nothing else in this
+    // process knows of it
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    // So it isn't leaked into anything we run in turn
+    let _ = SockRef::from(&fd).set_cloexec(true);
+    fd
+}
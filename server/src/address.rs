@@ -0,0 +1,47 @@
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+/// Either end of a TCP or Unix domain socket connection.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Address {
+    Inet(SocketAddr),
+    /// The socket's path, or `None` for the unnamed sockets clients usually connect from.
+    Unix(Option<PathBuf>),
+}
+
+impl Address {
+    /// The IP address of an internet socket, used for per-IP admission limits.
+    pub fn ip(&self) -> Option<IpAddr> {
+        self.as_inet().map(|addr| addr.ip())
+    }
+
+    pub fn as_inet(&self) -> Option<SocketAddr> {
+        match self {
+            Self::Inet(addr) => Some(*addr),
+            Self::Unix(_) => None,
+        }
+    }
+
+    pub(crate) fn from_unix(addr: tokio::net::unix::SocketAddr) -> Self {
+        Self::Unix(addr.as_pathname().map(PathBuf::from))
+    }
+}
+
+impl From<SocketAddr> for Address {
+    fn from(addr: SocketAddr) -> Self {
+        Self::Inet(addr)
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Inet(addr) => write!(f, "{}", addr),
+            Self::Unix(Some(path)) => write!(f, "unix:{}", path.display()),
+            Self::Unix(None) => f.write_str("unix"),
+        }
+    }
+}
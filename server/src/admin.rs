@@ -0,0 +1,118 @@
+use std::{io, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::{Connections, Metrics, exporter, log, log_error, log_info};
+
+const MAX_LINE_LENGTH: usize = 1024;
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const HELP: &str = "commands: list, kick <id>, metrics, log [level], help, quit";
+
+/// Serves the admin line protocol until aborted. Every command is answered with its
+/// output, if any, followed by a line starting with `OK` or `ERR`.
+pub(crate) fn spawn(
+    listener: TcpListener,
+    connections: Connections,
+    metrics: Metrics,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Ok(addr) = listener.local_addr() {
+            log_info!(addr, "Admin listener started");
+        }
+
+        loop {
+            let (stream, client_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log_error!("admin", format!("Accept error: {}", e));
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let connections = connections.clone();
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                log_info!(client_addr, "Admin session opened");
+                if let Err(e) = session(stream, &connections, &metrics).await {
+                    log_error!(client_addr, format!("Admin session error: {}", e));
+                }
+            });
+        }
+    })
+}
+
+async fn session(
+    stream: TcpStream,
+    connections: &Connections,
+    metrics: &Metrics,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        let mut limited = (&mut reader).take(MAX_LINE_LENGTH as u64 + 1);
+        if limited.read_line(&mut line).await? == 0 {
+            return Ok(());
+        }
+        if line.len() > MAX_LINE_LENGTH {
+            writer.write_all(b"ERR line too long\n").await?;
+            return Ok(());
+        }
+
+        let mut words = line.split_whitespace();
+        let reply = match (words.next(), words.next()) {
+            (None, _) => continue,
+            (Some("quit"), _) => return Ok(()),
+            (Some("list"), None) => list(connections),
+            (Some("kick"), Some(id)) => kick(connections, id),
+            (Some("metrics"), None) => format!("{}OK\n", exporter::render(metrics)),
+            (Some("log"), None) => format!("OK log level {}\n", log::level()),
+            (Some("log"), Some(level)) => match level.parse() {
+                Ok(level) => {
+                    log::set_level(level);
+                    log_info!("admin", format!("Log level set to {}", level));
+                    format!("OK log level {}\n", level)
+                }
+                Err(_) => "ERR expected error, warning, info or debug\n".to_string(),
+            },
+            (Some("help"), _) => format!("OK {}\n", HELP),
+            _ => format!("ERR unknown command, {}\n", HELP),
+        };
+        writer.write_all(reply.as_bytes()).await?;
+    }
+}
+
+fn list(connections: &Connections) -> String {
+    let live = connections.list();
+    let mut reply = String::new();
+    for conn in &live {
+        reply.push_str(&format!(
+            "{} peer={} server={} age={:.1}s received={} sent={}\n",
+            conn.id,
+            conn.peer,
+            conn.local,
+            conn.age.as_secs_f64(),
+            conn.bytes_received,
+            conn.bytes_sent
+        ));
+    }
+    reply.push_str(&format!("OK {} connection(s)\n", live.len()));
+    reply
+}
+
+fn kick(connections: &Connections, id: &str) -> String {
+    match id.parse() {
+        Ok(id) if connections.kick(id) => {
+            log_info!("admin", format!("Kicked connection #{}", id));
+            format!("OK kicked {}\n", id)
+        }
+        Ok(id) => format!("ERR no connection {}\n", id),
+        Err(_) => "ERR expected a connection ID\n".to_string(),
+    }
+}
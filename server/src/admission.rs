@@ -0,0 +1,331 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+// Idle rate-limit buckets are pruned once the table grows past this many IPs
+const MAX_TRACKED_IPS: usize = 4096;
+
+/// Limits applied to incoming TCP connections before a handler is spawned.
+#[derive(Debug, Clone, Default)]
+pub struct AdmissionLimits {
+    pub max_connections: Option<usize>,
+    pub max_connections_per_ip: Option<usize>,
+    /// Sustained connections per second accepted from one IP.
+    pub accept_rate_per_ip: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    TooManyConnections,
+    TooManyFromIp,
+    RateLimited,
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooManyConnections => write!(f, "connection limit reached"),
+            Self::TooManyFromIp => write!(f, "per-IP connection limit reached"),
+            Self::RateLimited => write!(f, "per-IP accept rate exceeded"),
+        }
+    }
+}
+
+pub(crate) struct Admission {
+    limits: AdmissionLimits,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    active: usize,
+    active_per_ip: HashMap<IpAddr, usize>,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Holds a connection slot until dropped.
+pub(crate) struct Permit {
+    admission: Arc<Admission>,
+    ip: Option<IpAddr>,
+}
+
+impl Admission {
+    pub(crate) fn new(limits: AdmissionLimits) -> Arc<Self> {
+        Arc::new(Self {
+            limits,
+            state: Mutex::new(State::default()),
+        })
+    }
+
+    /// Clients without an IP, i.e. on Unix domain sockets, only count towards
+    /// `max_connections`.
+    pub(crate) fn try_admit(self: &Arc<Self>, ip: Option<IpAddr>) -> Result<Permit, Rejection> {
+        let mut state = self.state.lock().unwrap();
+
+        if let Some(max) = self.limits.max_connections
+            && state.active >= max
+        {
+            return Err(Rejection::TooManyConnections);
+        }
+
+        if let Some(ip) = ip {
+            state.check_ip(ip, &self.limits)?;
+        }
+
+        state.active += 1;
+        state.add_ip(ip);
+        Ok(Permit {
+            admission: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Permit {
+    /// Moves the permit to `ip`, e.g. once a PROXY header has given the client's real
+    /// address, applying the per-IP limits to it. The global slot is kept.
+    pub(crate) fn rekey(&mut self, ip: Option<IpAddr>) -> Result<(), Rejection> {
+        if ip == self.ip {
+            return Ok(());
+        }
+        let mut state = self.admission.state.lock().unwrap();
+        if let Some(ip) = ip {
+            state.check_ip(ip, &self.admission.limits)?;
+        }
+        state.remove_ip(self.ip);
+        state.add_ip(ip);
+        self.ip = ip;
+        Ok(())
+    }
+}
+
+impl State {
+    fn check_ip(&mut self, ip: IpAddr, limits: &AdmissionLimits) -> Result<(), Rejection> {
+        let from_ip = self.active_per_ip.get(&ip).copied().unwrap_or(0);
+        if let Some(max) = limits.max_connections_per_ip
+            && from_ip >= max
+        {
+            return Err(Rejection::TooManyFromIp);
+        }
+
+        if let Some(rate) = limits.accept_rate_per_ip
+            && !self.take_token(ip, rate)
+        {
+            return Err(Rejection::RateLimited);
+        }
+        Ok(())
+    }
+
+    fn add_ip(&mut self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip {
+            *self.active_per_ip.entry(ip).or_insert(0) += 1;
+        }
+    }
+
+    fn remove_ip(&mut self, ip: Option<IpAddr>) {
+        if let Some(ip) = ip
+            && let Some(count) = self.active_per_ip.get_mut(&ip)
+        {
+            *count -= 1;
+            if *count == 0 {
+                self.active_per_ip.remove(&ip);
+            }
+        }
+    }
+
+    fn take_token(&mut self, ip: IpAddr, rate: f64) -> bool {
+        let now = Instant::now();
+        let capacity = rate.max(1.0);
+
+        if self.buckets.len() >= MAX_TRACKED_IPS && !self.buckets.contains_key(&ip) {
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < capacity
+            });
+        }
+
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.updated = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.admission.state.lock().unwrap();
+        state.active -= 1;
+        state.remove_ip(self.ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(n: usize) -> Option<IpAddr> {
+        Some(IpAddr::from([10, 0, (n >> 8) as u8, n as u8]))
+    }
+
+    /// Pretends `ip`'s bucket was last touched `ago` earlier.
+    fn age_bucket(admission: &Admission, ip: Option<IpAddr>, ago: Duration) {
+        let mut state = admission.state.lock().unwrap();
+        let bucket = state.buckets.get_mut(&ip.unwrap()).unwrap();
+        bucket.updated -= ago;
+    }
+
+    fn assert_idle(admission: &Admission) {
+        let state = admission.state.lock().unwrap();
+        assert_eq!(state.active, 0);
+        assert!(state.active_per_ip.is_empty());
+    }
+
+    #[test]
+    fn global_limit() {
+        let admission = Admission::new(AdmissionLimits {
+            max_connections: Some(2),
+            ..AdmissionLimits::default()
+        });
+        let first = admission.try_admit(ip(1)).unwrap();
+        let _second = admission.try_admit(None).unwrap();
+        assert_eq!(
+            admission.try_admit(ip(2)).err(),
+            Some(Rejection::TooManyConnections)
+        );
+
+        drop(first);
+        let _third = admission.try_admit(ip(2)).unwrap();
+    }
+
+    #[test]
+    fn per_ip_limit() {
+        let admission = Admission::new(AdmissionLimits {
+            max_connections_per_ip: Some(2),
+            ..AdmissionLimits::default()
+        });
+        let permits: Vec<_> = (0..2)
+            .map(|_| admission.try_admit(ip(1)).unwrap())
+            .collect();
+        assert_eq!(
+            admission.try_admit(ip(1)).err(),
+            Some(Rejection::TooManyFromIp)
+        );
+        let other = admission.try_admit(ip(2)).unwrap();
+        // Unix clients have no IP to limit
+        let unix: Vec<_> = (0..3).map(|_| admission.try_admit(None).unwrap()).collect();
+
+        drop(permits);
+        let again = admission.try_admit(ip(1)).unwrap();
+        drop((again, other, unix));
+        assert_idle(&admission);
+    }
+
+    #[test]
+    fn token_bucket_refills() {
+        let admission = Admission::new(AdmissionLimits {
+            accept_rate_per_ip: Some(2.0),
+            ..AdmissionLimits::default()
+        });
+        // A full bucket allows a burst of one second's worth
+        admission.try_admit(ip(1)).unwrap();
+        admission.try_admit(ip(1)).unwrap();
+        assert_eq!(
+            admission.try_admit(ip(1)).err(),
+            Some(Rejection::RateLimited)
+        );
+        admission.try_admit(ip(2)).unwrap();
+
+        age_bucket(&admission, ip(1), Duration::from_millis(500));
+        admission.try_admit(ip(1)).unwrap();
+        assert_eq!(
+            admission.try_admit(ip(1)).err(),
+            Some(Rejection::RateLimited)
+        );
+
+        // Refills stop at the burst size
+        age_bucket(&admission, ip(1), Duration::from_secs(60));
+        for _ in 0..2 {
+            admission.try_admit(ip(1)).unwrap();
+        }
+        assert!(admission.try_admit(ip(1)).is_err());
+        assert_idle(&admission);
+    }
+
+    #[test]
+    fn slow_rates_still_admit_one() {
+        let admission = Admission::new(AdmissionLimits {
+            accept_rate_per_ip: Some(0.5),
+            ..AdmissionLimits::default()
+        });
+        admission.try_admit(ip(1)).unwrap();
+        age_bucket(&admission, ip(1), Duration::from_secs(1));
+        assert!(admission.try_admit(ip(1)).is_err());
+        age_bucket(&admission, ip(1), Duration::from_secs(1));
+        admission.try_admit(ip(1)).unwrap();
+    }
+
+    #[test]
+    fn full_buckets_are_pruned() {
+        let admission = Admission::new(AdmissionLimits {
+            accept_rate_per_ip: Some(1.0),
+            ..AdmissionLimits::default()
+        });
+        for n in 0..MAX_TRACKED_IPS {
+            admission.try_admit(ip(n)).unwrap();
+        }
+        // Half of them have since refilled and can be forgotten
+        for n in (0..MAX_TRACKED_IPS).step_by(2) {
+            age_bucket(&admission, ip(n), Duration::from_secs(1));
+        }
+        admission.try_admit(ip(MAX_TRACKED_IPS)).unwrap();
+
+        let state = admission.state.lock().unwrap();
+        assert_eq!(state.buckets.len(), MAX_TRACKED_IPS / 2 + 1);
+        assert!(state.buckets.contains_key(&ip(1).unwrap()));
+        assert!(!state.buckets.contains_key(&ip(0).unwrap()));
+    }
+
+    #[test]
+    fn rekey_moves_the_per_ip_count() {
+        let admission = Admission::new(AdmissionLimits {
+            max_connections: Some(3),
+            max_connections_per_ip: Some(1),
+            ..AdmissionLimits::default()
+        });
+        let mut first = admission.try_admit(None).unwrap();
+        first.rekey(ip(1)).unwrap();
+        first.rekey(ip(1)).unwrap();
+
+        let mut second = admission.try_admit(None).unwrap();
+        assert_eq!(second.rekey(ip(1)), Err(Rejection::TooManyFromIp));
+        second.rekey(ip(2)).unwrap();
+        {
+            let state = admission.state.lock().unwrap();
+            assert_eq!(state.active, 2);
+            assert_eq!(state.active_per_ip.len(), 2);
+        }
+
+        drop((first, second));
+        assert_idle(&admission);
+    }
+}
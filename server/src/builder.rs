@@ -0,0 +1,396 @@
+use std::{io, net::SocketAddr, path::PathBuf, time::Duration};
+use tokio::{net::TcpListener, task::JoinHandle};
+use tokio_rustls::TlsAcceptor;
+
+use crate::{
+    Address, Connections, DatagramLimits, Faults, Metrics, ServerConfig, Shutdown, TcpHandler,
+    Timeouts, TlsFiles, UdpHandler,
+    activation::{self, Offer},
+    admin,
+    admission::AdmissionLimits,
+    config::DEFAULT_STATS_INTERVAL,
+    exporter,
+    listener::{Listener, UNIX_PREFIX, split_addrs},
+    proxy_protocol::IpNet,
+    serve_tcp, serve_udp,
+};
+
+/// Binds listeners and spawns the accept loop, returning a handle to the running server.
+///
+/// The address may list several comma-separated addresses, each `host:port` or
+/// `unix:<path>`, which TCP servers all accept from. UDP servers take a single
+/// `host:port`. Binding to port 0 picks an ephemeral port, which is reported by
+/// [`ServerHandle::local_addrs`]. Sockets passed in by systemd or a previous process
+/// are used instead of binding; see [`activation`].
+pub struct ServerBuilder {
+    addr: String,
+    shutdown: Shutdown,
+    grace_period: Option<Duration>,
+    metrics: Option<Metrics>,
+    metrics_addr: Option<String>,
+    admin_addr: Option<String>,
+    tls: Option<TlsFiles>,
+    options: ServeOptions,
+}
+
+/// Per-server settings handed to the accept loops.
+#[derive(Clone)]
+pub(crate) struct ServeOptions {
+    /// How often to print stats, `None` for never.
+    pub(crate) stats_interval: Option<Duration>,
+    /// Whether to print stats after draining, `false` when the owner of shared metrics
+    /// reports them.
+    pub(crate) final_stats: bool,
+    pub(crate) admission: AdmissionLimits,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) datagram_limits: DatagramLimits,
+    pub(crate) tls: Option<TlsAcceptor>,
+    pub(crate) capture_dir: Option<PathBuf>,
+    pub(crate) connections: Connections,
+    pub(crate) faults: Faults,
+    pub(crate) proxy_protocol: Vec<IpNet>,
+    /// Index of this server among per-core shards, which bind with `SO_REUSEPORT`.
+    pub(crate) shard: Option<usize>,
+}
+
+impl ServerBuilder {
+    pub fn new(addr: impl Into<String>) -> Self {
+        Self {
+            addr: addr.into(),
+            shutdown: Shutdown::new(),
+            grace_period: None,
+            metrics: None,
+            metrics_addr: None,
+            admin_addr: None,
+            tls: None,
+            options: ServeOptions {
+                stats_interval: Some(DEFAULT_STATS_INTERVAL),
+                final_stats: true,
+                admission: AdmissionLimits::default(),
+                timeouts: Timeouts::default(),
+                datagram_limits: DatagramLimits::default(),
+                tls: None,
+                capture_dir: None,
+                connections: Connections::new(),
+                faults: Faults::default(),
+                proxy_protocol: Vec::new(),
+                shard: None,
+            },
+        }
+    }
+
+    pub fn from_config(config: &ServerConfig) -> Self {
+        let builder = Self::new(config.addr.clone())
+            .grace_period(config.grace_period)
+            .stats_interval(config.stats_interval)
+            .admission(config.admission.clone())
+            .timeouts(config.timeouts)
+            .datagram_limits(config.datagram_limits)
+            .faults(config.faults)
+            .proxy_protocol(config.proxy_protocol.clone());
+
+        let builder = match &config.metrics_addr {
+            Some(addr) => builder.metrics_addr(addr.clone()),
+            None => builder,
+        };
+
+        let builder = match &config.admin_addr {
+            Some(addr) => builder.admin_addr(addr.clone()),
+            None => builder,
+        };
+
+        let builder = match &config.capture_dir {
+            Some(dir) => builder.capture_dir(dir.clone()),
+            None => builder,
+        };
+
+        match config.tls_files() {
+            Some(files) => builder.tls(files),
+            None => builder,
+        }
+    }
+
+    pub fn shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    pub fn grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = Some(grace_period);
+        self
+    }
+
+    /// Prints stats this often; zero turns the periodic report off, leaving only the
+    /// one printed after shutdown.
+    pub fn stats_interval(mut self, stats_interval: Duration) -> Self {
+        self.options.stats_interval = Some(stats_interval).filter(|interval| !interval.is_zero());
+        self
+    }
+
+    /// Counts into existing metrics, e.g. ones shared by several servers. The server
+    /// then leaves printing stats to the caller.
+    pub fn metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = Some(metrics);
+        self.options.stats_interval = None;
+        self.options.final_stats = false;
+        self
+    }
+
+    /// Limits concurrent connections and accept rate for TCP servers.
+    pub fn admission(mut self, limits: AdmissionLimits) -> Self {
+        self.options.admission = limits;
+        self
+    }
+
+    /// Serves Prometheus metrics over HTTP on `addr` alongside the server.
+    pub fn metrics_addr(mut self, addr: impl Into<String>) -> Self {
+        self.metrics_addr = Some(addr.into());
+        self
+    }
+
+    /// Serves the admin line protocol on `addr`, for listing and kicking connections,
+    /// dumping metrics and changing the log level. Bind it to a trusted interface.
+    pub fn admin_addr(mut self, addr: impl Into<String>) -> Self {
+        self.admin_addr = Some(addr.into());
+        self
+    }
+
+    /// Registers connections in an existing registry, e.g. one shared by several
+    /// servers behind a single admin listener.
+    pub fn connections(mut self, connections: Connections) -> Self {
+        self.options.connections = connections;
+        self
+    }
+
+    /// Read and session deadlines applied to every TCP connection.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.options.timeouts = timeouts;
+        self
+    }
+
+    /// Maximum datagram size for UDP servers and what to do with longer ones.
+    pub fn datagram_limits(mut self, limits: DatagramLimits) -> Self {
+        self.options.datagram_limits = limits;
+        self
+    }
+
+    /// Terminates TLS on every TCP connection before it reaches the handler. Unix domain
+    /// socket connections are served without it.
+    pub fn tls(mut self, files: TlsFiles) -> Self {
+        self.tls = Some(files);
+        self
+    }
+
+    /// Records the traffic of every connection or UDP peer into a file under `dir`.
+    /// See [`capture`](crate::capture) for the format.
+    pub fn capture_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.options.capture_dir = Some(dir.into());
+        self
+    }
+
+    /// Injects network faults into every TCP stream and UDP datagram, for testing.
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.options.faults = faults;
+        self
+    }
+
+    /// Expects connections from these networks, e.g. a load balancer's, to open with a
+    /// PROXY protocol header and serves them as coming from the client it names.
+    /// Connections whose header is missing or malformed are dropped.
+    pub fn proxy_protocol(mut self, trusted: Vec<IpNet>) -> Self {
+        self.options.proxy_protocol = trusted;
+        self
+    }
+
+    /// Serves as shard `index` of a server spread across cores: addresses are bound with
+    /// `SO_REUSEPORT` so every shard can listen on them, and traffic is counted per
+    /// shard. See [`runtime`](crate::runtime).
+    pub fn shard(mut self, index: usize) -> Self {
+        self.options.shard = Some(index);
+        self
+    }
+
+    pub async fn tcp<H: TcpHandler>(mut self, handler: H) -> io::Result<ServerHandle> {
+        if let Some(files) = &self.tls {
+            self.options.tls = Some(files.acceptor()?);
+        }
+        let mut listeners = Vec::new();
+        for addr in split_addrs(&self.addr) {
+            listeners.push(Listener::bind(addr, self.options.shard.is_some()).await?);
+        }
+        if listeners.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no address to listen on",
+            ));
+        }
+        let local_addrs = listeners
+            .iter()
+            .map(Listener::local_addr)
+            .collect::<io::Result<_>>()?;
+        let prepared = self.prepare().await?;
+
+        let serve = serve_tcp(
+            listeners,
+            handler,
+            prepared.metrics.clone(),
+            prepared.shutdown.clone(),
+            prepared.options.clone(),
+        );
+
+        Ok(prepared.spawn(local_addrs, serve))
+    }
+
+    pub async fn udp<H: UdpHandler>(self, handler: H) -> io::Result<ServerHandle> {
+        let addr = match split_addrs(&self.addr).collect::<Vec<_>>()[..] {
+            [addr] if !addr.starts_with(UNIX_PREFIX) => addr,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("UDP servers take a single host:port, not {}", self.addr),
+                ));
+            }
+        };
+        let (socket, offer) = activation::udp_socket(addr, self.options.shard.is_some()).await?;
+        let local_addr = socket.local_addr()?;
+        let mut prepared = self.prepare().await?;
+        prepared.offers.push(offer);
+
+        let serve = serve_udp(
+            socket,
+            handler,
+            prepared.metrics.clone(),
+            prepared.shutdown.clone(),
+            prepared.options.clone(),
+        );
+
+        Ok(prepared.spawn(vec![local_addr.into()], serve))
+    }
+
+    async fn prepare(self) -> io::Result<Prepared> {
+        let shutdown = match self.grace_period {
+            Some(grace_period) => self.shutdown.with_grace_period(grace_period),
+            None => self.shutdown,
+        };
+
+        let mut offers = Vec::new();
+        let exporter = match &self.metrics_addr {
+            Some(addr) => {
+                let (listener, offer) = activation::tcp_listener(addr).await?;
+                offers.push(offer);
+                let local_addr = listener.local_addr()?;
+                Some((listener, local_addr))
+            }
+            None => None,
+        };
+        let admin = match &self.admin_addr {
+            Some(addr) => {
+                let (listener, offer) = activation::tcp_listener(addr).await?;
+                offers.push(offer);
+                Some(listener)
+            }
+            None => None,
+        };
+
+        Ok(Prepared {
+            metrics: self.metrics.unwrap_or_default(),
+            shutdown,
+            options: self.options,
+            exporter,
+            admin,
+            offers,
+        })
+    }
+}
+
+struct Prepared {
+    metrics: Metrics,
+    shutdown: Shutdown,
+    options: ServeOptions,
+    exporter: Option<(TcpListener, SocketAddr)>,
+    admin: Option<TcpListener>,
+    offers: Vec<Offer>,
+}
+
+impl Prepared {
+    fn spawn<S>(self, local_addrs: Vec<Address>, serve: S) -> ServerHandle
+    where
+        S: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        let metrics_addr = self.exporter.as_ref().map(|(_, addr)| *addr);
+        let exporter = self.exporter.map(|(listener, _)| {
+            exporter::spawn(listener, self.metrics.clone(), self.shutdown.clone())
+        });
+        let admin = self.admin.map(|listener| {
+            admin::spawn(
+                listener,
+                self.options.connections.clone(),
+                self.metrics.clone(),
+            )
+        });
+
+        // The exporter and admin listener keep answering while handlers drain and stop
+        // with the server
+        let offers = self.offers;
+        let task = tokio::spawn(async move {
+            let result = serve.await;
+            for task in exporter.into_iter().chain(admin) {
+                task.abort();
+            }
+            drop(offers);
+            result
+        });
+
+        ServerHandle {
+            local_addrs,
+            metrics_addr,
+            metrics: self.metrics,
+            shutdown: self.shutdown,
+            task,
+        }
+    }
+}
+
+/// A server running in the background on the current runtime.
+pub struct ServerHandle {
+    local_addrs: Vec<Address>,
+    metrics_addr: Option<SocketAddr>,
+    metrics: Metrics,
+    shutdown: Shutdown,
+    task: JoinHandle<io::Result<()>>,
+}
+
+impl ServerHandle {
+    /// The first address the server listens on.
+    pub fn local_addr(&self) -> &Address {
+        &self.local_addrs[0]
+    }
+
+    pub fn local_addrs(&self) -> &[Address] {
+        &self.local_addrs
+    }
+
+    /// Address of the metrics exporter, if one was requested.
+    pub fn metrics_addr(&self) -> Option<SocketAddr> {
+        self.metrics_addr
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn shutdown_handle(&self) -> Shutdown {
+        self.shutdown.clone()
+    }
+
+    /// Stops accepting, drains the handlers and waits for the server to exit.
+    pub async fn shutdown(self) -> io::Result<()> {
+        self.shutdown.trigger();
+        self.wait().await
+    }
+
+    /// Waits for the server to exit without triggering shutdown.
+    pub async fn wait(self) -> io::Result<()> {
+        self.task.await.map_err(io::Error::other)?
+    }
+}
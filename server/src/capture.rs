@@ -0,0 +1,505 @@
+//! Opt-in recording of the raw bytes exchanged with each client.
+//!
+//! Every TCP connection gets its own file; UDP datagrams are grouped into one file per
+//! peer address. A file starts with a header followed by one record per read or write:
+//!
+//! ```text
+//! header: b"PHCAP1", transport (u8: 0 = TCP, 1 = UDP),
+//!         local and peer address (each a u8 length then UTF-8 text)
+//! record: time (u64 microseconds since the UNIX epoch), direction (u8: 0 = received,
+//!         1 = sent), length (u32), then the bytes
+//! ```
+//!
+//! Integers are big-endian. TCP traffic is recorded after TLS decryption, and an empty
+//! received record marks the client closing its side of the connection.
+//!
+//! Files are written by a thread per server, so connections never wait on the disk. A
+//! TCP connection whose records arrive faster than they can be written stops being
+//! captured; for UDP the datagrams that don't fit are left out.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc::{self, Receiver, Sender, error::TrySendError};
+
+use crate::{Address, log_warning};
+
+const MAGIC: &[u8; 6] = b"PHCAP1";
+// Records waiting for the writer thread
+const QUEUE_CAPACITY: usize = 4096;
+// UDP files held open at once; the least recently written is closed first
+const MAX_OPEN_UDP_FILES: usize = 64;
+// Capture directories opened by this process, numbering their file names
+static SERVERS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp = 0,
+    Udp = 1,
+}
+
+/// Which way bytes went, as seen by the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Received = 0,
+    Sent = 1,
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub time: SystemTime,
+    pub direction: Direction,
+    pub data: Vec<u8>,
+}
+
+/// The contents of one capture file.
+#[derive(Debug, Clone)]
+pub struct Transcript {
+    pub transport: Transport,
+    pub local_addr: String,
+    pub peer_addr: String,
+    pub records: Vec<Record>,
+}
+
+/// Hands one server's records to its writer thread.
+#[derive(Debug, Clone)]
+pub(crate) struct CaptureDir {
+    jobs: Sender<Job>,
+}
+
+/// Passes the records of one TCP connection to the writer, closing its file on drop.
+pub(crate) struct Recorder {
+    id: u64,
+    jobs: Sender<Job>,
+    peer: Address,
+}
+
+#[derive(Debug)]
+enum Job {
+    Open {
+        id: u64,
+        local: Address,
+        peer: Address,
+    },
+    Record {
+        id: u64,
+        record: Record,
+    },
+    Close {
+        id: u64,
+    },
+    Datagram {
+        local: SocketAddr,
+        peer: SocketAddr,
+        record: Record,
+    },
+}
+
+/// Owns the open capture files of one server.
+struct Writer {
+    dir: PathBuf,
+    // Keeps files from different runs and servers apart
+    prefix: String,
+    tcp: HashMap<u64, OpenFile>,
+    udp: HashMap<SocketAddr, OpenFile>,
+    // Counts writes, so UDP files can be closed least recently written first
+    writes: u64,
+}
+
+struct OpenFile {
+    file: BufWriter<File>,
+    peer: Address,
+    last_write: u64,
+}
+
+impl Transcript {
+    /// Whether `bytes` start like a capture file, complete or not.
+    pub fn is_capture(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::from_reader(BufReader::new(File::open(path)?))
+    }
+
+    pub fn from_reader(mut reader: impl Read) -> io::Result<Self> {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a capture file"));
+        }
+
+        let transport = match read_u8(&mut reader)? {
+            0 => Transport::Tcp,
+            1 => Transport::Udp,
+            _ => return Err(invalid("unknown transport")),
+        };
+        let local_addr = read_addr(&mut reader)?;
+        let peer_addr = read_addr(&mut reader)?;
+
+        let mut records = Vec::new();
+        loop {
+            let mut micros = [0; 8];
+            match reader.read_exact(&mut micros) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e),
+            }
+            let time = UNIX_EPOCH + Duration::from_micros(u64::from_be_bytes(micros));
+            let direction = match read_u8(&mut reader)? {
+                0 => Direction::Received,
+                1 => Direction::Sent,
+                _ => return Err(invalid("unknown direction")),
+            };
+            let mut len = [0; 4];
+            reader.read_exact(&mut len)?;
+            let mut data = vec![0; u32::from_be_bytes(len) as usize];
+            reader.read_exact(&mut data)?;
+
+            records.push(Record {
+                time,
+                direction,
+                data,
+            });
+        }
+
+        Ok(Self {
+            transport,
+            local_addr,
+            peer_addr,
+            records,
+        })
+    }
+}
+
+impl Record {
+    fn now(direction: Direction, data: &[u8]) -> Self {
+        Self {
+            time: SystemTime::now(),
+            direction,
+            data: data.to_vec(),
+        }
+    }
+}
+
+impl CaptureDir {
+    /// Starts the writer thread, which runs until this and every [`Recorder`] are gone.
+    pub(crate) fn create(dir: &Path, local_addr: &Address) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        // A handover's replacement and per-core shards start within the same second, so
+        // the pid and a count of servers in this process keep their files apart
+        let run = format!(
+            "{}-{}-{}",
+            started,
+            process::id(),
+            SERVERS.fetch_add(1, Ordering::Relaxed)
+        );
+
+        let writer = Writer {
+            dir: dir.to_path_buf(),
+            prefix: match local_addr.as_inet() {
+                Some(addr) => format!("{}-{}", run, addr.port()),
+                None => format!("{}-unix", run),
+            },
+            tcp: HashMap::new(),
+            udp: HashMap::new(),
+            writes: 0,
+        };
+        let (jobs, queued) = mpsc::channel(QUEUE_CAPACITY);
+        thread::Builder::new()
+            .name("capture".to_string())
+            .spawn(move || writer.run(queued))?;
+        Ok(Self { jobs })
+    }
+
+    pub(crate) fn tcp(&self, id: u64, local: &Address, peer: Address) -> io::Result<Recorder> {
+        let local = local.clone();
+        let open = Job::Open {
+            id,
+            local,
+            peer: peer.clone(),
+        };
+        self.jobs.try_send(open).map_err(not_queued)?;
+        Ok(Recorder {
+            id,
+            jobs: self.jobs.clone(),
+            peer,
+        })
+    }
+
+    /// Queues one datagram for the file of `peer`, which is created on first use.
+    pub(crate) fn udp(
+        &self,
+        local: SocketAddr,
+        peer: SocketAddr,
+        direction: Direction,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let record = Record::now(direction, data);
+        let datagram = Job::Datagram {
+            local,
+            peer,
+            record,
+        };
+        self.jobs.try_send(datagram).map_err(not_queued)
+    }
+}
+
+impl Recorder {
+    pub(crate) fn record(&mut self, direction: Direction, data: &[u8]) -> io::Result<()> {
+        let record = Record::now(direction, data);
+        let job = Job::Record {
+            id: self.id,
+            record,
+        };
+        self.jobs.try_send(job).map_err(not_queued)
+    }
+
+    pub(crate) fn peer(&self) -> &Address {
+        &self.peer
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        let close = Job::Close { id: self.id };
+        // Without room now, wait for some rather than leave the file open
+        if let Err(TrySendError::Full(close)) = self.jobs.try_send(close)
+            && let Ok(runtime) = tokio::runtime::Handle::try_current()
+        {
+            let jobs = self.jobs.clone();
+            runtime.spawn(async move { jobs.send(close).await });
+        }
+    }
+}
+
+impl Writer {
+    fn run(mut self, mut queued: Receiver<Job>) {
+        while let Some(job) = queued.blocking_recv() {
+            self.apply(job);
+            // Flushing once the queue is drained keeps the files current without
+            // flushing after every record
+            while let Ok(job) = queued.try_recv() {
+                self.apply(job);
+            }
+            self.flush();
+        }
+    }
+
+    fn apply(&mut self, job: Job) {
+        match job {
+            Job::Open { id, local, peer } => {
+                let path = self.dir.join(format!("{}-tcp-{}.cap", self.prefix, id));
+                let opened = File::create_new(path).and_then(|file| {
+                    let mut file = BufWriter::new(file);
+                    write_header(&mut file, Transport::Tcp, &local, &peer)?;
+                    Ok(file)
+                });
+                match opened {
+                    Ok(file) => {
+                        let file = OpenFile {
+                            file,
+                            peer,
+                            last_write: self.writes,
+                        };
+                        self.tcp.insert(id, file);
+                    }
+                    Err(e) => log_warning!(peer, format!("Traffic capture failed: {}", e)),
+                }
+            }
+            Job::Record { id, record } => {
+                self.writes += 1;
+                if let Some(file) = self.tcp.get_mut(&id)
+                    && let Err(e) = write_record(&mut file.file, &record)
+                {
+                    log_warning!(file.peer, format!("Traffic capture stopped: {}", e));
+                    self.tcp.remove(&id);
+                }
+            }
+            Job::Close { id } => {
+                if let Some(mut file) = self.tcp.remove(&id) {
+                    file.flush();
+                }
+            }
+            Job::Datagram {
+                local,
+                peer,
+                record,
+            } => {
+                self.writes += 1;
+                let result = self
+                    .udp_file(local, peer)
+                    .and_then(|file| write_record(&mut file.file, &record));
+                if let Err(e) = result {
+                    log_warning!(peer, format!("Traffic capture failed: {}", e));
+                    self.udp.remove(&peer);
+                }
+            }
+        }
+    }
+
+    fn udp_file(&mut self, local: SocketAddr, peer: SocketAddr) -> io::Result<&mut OpenFile> {
+        if !self.udp.contains_key(&peer) {
+            if self.udp.len() >= MAX_OPEN_UDP_FILES
+                && let Some(oldest) = self
+                    .udp
+                    .iter()
+                    .min_by_key(|(_, file)| file.last_write)
+                    .map(|(peer, _)| *peer)
+                && let Some(mut file) = self.udp.remove(&oldest)
+            {
+                file.flush();
+            }
+
+            let name = format!("{}-udp-{}.cap", self.prefix, peer).replace([':', '[', ']'], "_");
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join(name))?;
+            let is_new = file.metadata()?.len() == 0;
+            let mut file = BufWriter::new(file);
+            if is_new {
+                write_header(&mut file, Transport::Udp, &local.into(), &peer.into())?;
+            }
+            let file = OpenFile {
+                file,
+                peer: peer.into(),
+                last_write: self.writes,
+            };
+            self.udp.insert(peer, file);
+        }
+
+        let file = self.udp.get_mut(&peer).unwrap();
+        file.last_write = self.writes;
+        Ok(file)
+    }
+
+    fn flush(&mut self) {
+        self.tcp.retain(|_, file| file.flush());
+        self.udp.retain(|_, file| file.flush());
+    }
+}
+
+impl OpenFile {
+    /// Returns whether the file is still usable.
+    fn flush(&mut self) -> bool {
+        match self.file.flush() {
+            Ok(()) => true,
+            Err(e) => {
+                log_warning!(self.peer, format!("Traffic capture stopped: {}", e));
+                false
+            }
+        }
+    }
+}
+
+fn not_queued(e: TrySendError<Job>) -> io::Error {
+    match e {
+        TrySendError::Full(_) => io::Error::other("capture writer is falling behind"),
+        TrySendError::Closed(_) => io::Error::other("capture writer has stopped"),
+    }
+}
+
+fn write_header(
+    out: &mut impl Write,
+    transport: Transport,
+    local: &Address,
+    peer: &Address,
+) -> io::Result<()> {
+    out.write_all(MAGIC)?;
+    out.write_all(&[transport as u8])?;
+    for addr in [local, peer] {
+        let addr = addr.to_string();
+        let len = u8::try_from(addr.len()).map_err(|_| invalid("address too long"))?;
+        out.write_all(&[len])?;
+        out.write_all(addr.as_bytes())?;
+    }
+    Ok(())
+}
+
+fn write_record(out: &mut impl Write, record: &Record) -> io::Result<()> {
+    let Record {
+        time,
+        direction,
+        data,
+    } = record;
+    let micros = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64;
+    let len = u32::try_from(data.len()).map_err(|_| invalid("record too long"))?;
+
+    out.write_all(&micros.to_be_bytes())?;
+    out.write_all(&[*direction as u8])?;
+    out.write_all(&len.to_be_bytes())?;
+    out.write_all(data)
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_addr(reader: &mut impl Read) -> io::Result<String> {
+    let mut addr = vec![0; read_u8(reader)? as usize];
+    reader.read_exact(&mut addr)?;
+    String::from_utf8(addr).map_err(|_| invalid("address is not UTF-8"))
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let local: SocketAddr = "127.0.0.1:8000".parse().unwrap();
+        let peer: SocketAddr = "[::1]:40000".parse().unwrap();
+        let mut file = Vec::new();
+        write_header(&mut file, Transport::Udp, &local.into(), &peer.into()).unwrap();
+        write_record(&mut file, &Record::now(Direction::Received, b"hello")).unwrap();
+        write_record(&mut file, &Record::now(Direction::Sent, b"")).unwrap();
+        assert!(Transcript::is_capture(&file));
+
+        let transcript = Transcript::from_reader(&file[..]).unwrap();
+        assert_eq!(transcript.transport, Transport::Udp);
+        assert_eq!(transcript.local_addr, "127.0.0.1:8000");
+        assert_eq!(transcript.peer_addr, "[::1]:40000");
+        let records: Vec<_> = transcript
+            .records
+            .iter()
+            .map(|record| (record.direction, record.data.as_slice()))
+            .collect();
+        assert_eq!(
+            records,
+            [
+                (Direction::Received, &b"hello"[..]),
+                (Direction::Sent, &b""[..])
+            ]
+        );
+    }
+
+    #[test]
+    fn address_too_long() {
+        let local = Address::Unix(Some(PathBuf::from("x".repeat(300))));
+        let peer = Address::Unix(None);
+        let e = write_header(&mut Vec::new(), Transport::Tcp, &local, &peer).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,417 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder};
+
+use crate::ConnectionContext;
+
+pub use futures_util::{SinkExt, StreamExt};
+pub use tokio_util::codec::{Framed, FramedRead, FramedWrite};
+
+/// Frames separated by a delimiter, which is stripped on decode and appended on encode.
+///
+/// A trailing frame without a delimiter is still returned at end of stream, as
+/// `read_line` would, and a frame longer than `max_length` fails with `InvalidData`.
+#[derive(Debug, Clone)]
+pub struct DelimiterCodec {
+    delimiter: Vec<u8>,
+    max_length: usize,
+    // How far into the buffer has already been searched for the delimiter
+    next_index: usize,
+}
+
+impl DelimiterCodec {
+    pub fn new(delimiter: &[u8], max_length: usize) -> Self {
+        assert!(!delimiter.is_empty(), "delimiter must not be empty");
+        Self {
+            delimiter: delimiter.to_vec(),
+            max_length,
+            next_index: 0,
+        }
+    }
+
+    /// Newline-delimited frames.
+    pub fn lines(max_length: usize) -> Self {
+        Self::new(b"\n", max_length)
+    }
+}
+
+impl Decoder for DelimiterCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        let start = self.next_index.saturating_sub(self.delimiter.len() - 1);
+        let found = src[start..]
+            .windows(self.delimiter.len())
+            .position(|window| window == self.delimiter.as_slice())
+            .map(|pos| start + pos);
+
+        match found {
+            Some(pos) if pos <= self.max_length => {
+                self.next_index = 0;
+                let frame = src.split_to(pos).freeze();
+                src.advance(self.delimiter.len());
+                Ok(Some(frame))
+            }
+            Some(_) => Err(frame_too_long(self.max_length)),
+            None if src.len() >= self.max_length + self.delimiter.len() => {
+                Err(frame_too_long(self.max_length))
+            }
+            None => {
+                self.next_index = src.len();
+                Ok(None)
+            }
+        }
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        if let Some(frame) = self.decode(src)? {
+            return Ok(Some(frame));
+        }
+        self.next_index = 0;
+        if src.is_empty() {
+            return Ok(None);
+        }
+        if src.len() > self.max_length {
+            return Err(frame_too_long(self.max_length));
+        }
+        Ok(Some(src.split().freeze()))
+    }
+}
+
+impl Encoder<&[u8]> for DelimiterCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        if item.len() > self.max_length {
+            return Err(frame_too_long(self.max_length));
+        }
+        dst.reserve(item.len() + self.delimiter.len());
+        dst.put_slice(item);
+        dst.put_slice(&self.delimiter);
+        Ok(())
+    }
+}
+
+/// Frames of exactly `size` bytes, which must not be zero.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedSizeCodec {
+    size: usize,
+}
+
+impl FixedSizeCodec {
+    pub fn new(size: usize) -> Self {
+        assert!(size > 0, "frame size must not be zero");
+        Self { size }
+    }
+}
+
+impl Decoder for FixedSizeCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        if src.len() < self.size {
+            src.reserve(self.size - src.len());
+            return Ok(None);
+        }
+        Ok(Some(src.split_to(self.size).freeze()))
+    }
+}
+
+impl Encoder<&[u8]> for FixedSizeCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        if item.len() != self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame is {} bytes, expected {}", item.len(), self.size),
+            ));
+        }
+        dst.put_slice(item);
+        Ok(())
+    }
+}
+
+/// Width of the big-endian length header used by [`LengthPrefixedCodec`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    U8,
+    U16,
+    U32,
+}
+
+impl LengthPrefix {
+    fn size(self) -> usize {
+        match self {
+            Self::U8 => 1,
+            Self::U16 => 2,
+            Self::U32 => 4,
+        }
+    }
+
+    fn max_value(self) -> usize {
+        match self {
+            Self::U8 => u8::MAX as usize,
+            Self::U16 => u16::MAX as usize,
+            Self::U32 => u32::MAX as usize,
+        }
+    }
+}
+
+/// Frames preceded by their payload length.
+#[derive(Debug, Clone, Copy)]
+pub struct LengthPrefixedCodec {
+    prefix: LengthPrefix,
+    max_length: usize,
+}
+
+impl LengthPrefixedCodec {
+    pub fn new(prefix: LengthPrefix, max_length: usize) -> Self {
+        Self {
+            prefix,
+            max_length: max_length.min(prefix.max_value()),
+        }
+    }
+}
+
+impl Decoder for LengthPrefixedCodec {
+    type Item = Bytes;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> io::Result<Option<Bytes>> {
+        let header = self.prefix.size();
+        if src.len() < header {
+            return Ok(None);
+        }
+
+        let length = match self.prefix {
+            LengthPrefix::U8 => src[0] as usize,
+            LengthPrefix::U16 => u16::from_be_bytes([src[0], src[1]]) as usize,
+            LengthPrefix::U32 => u32::from_be_bytes([src[0], src[1], src[2], src[3]]) as usize,
+        };
+        if length > self.max_length {
+            return Err(frame_too_long(self.max_length));
+        }
+
+        if src.len() < header + length {
+            src.reserve(header + length - src.len());
+            return Ok(None);
+        }
+
+        src.advance(header);
+        Ok(Some(src.split_to(length).freeze()))
+    }
+}
+
+impl Encoder<&[u8]> for LengthPrefixedCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: &[u8], dst: &mut BytesMut) -> io::Result<()> {
+        if item.len() > self.max_length {
+            return Err(frame_too_long(self.max_length));
+        }
+
+        dst.reserve(self.prefix.size() + item.len());
+        match self.prefix {
+            LengthPrefix::U8 => dst.put_u8(item.len() as u8),
+            LengthPrefix::U16 => dst.put_u16(item.len() as u16),
+            LengthPrefix::U32 => dst.put_u32(item.len() as u32),
+        }
+        dst.put_slice(item);
+        Ok(())
+    }
+}
+
+/// Wraps a codec so decoded and encoded frames are counted as the connection's
+/// messages, and the size of each decoded frame observed in the message size histogram.
+/// Bytes are counted by the [`Stream`](crate::Stream) underneath.
+#[derive(Debug, Clone)]
+pub struct Metered<C> {
+    inner: C,
+    context: ConnectionContext,
+}
+
+impl<C> Metered<C> {
+    pub fn new(inner: C, context: ConnectionContext) -> Self {
+        Self { inner, context }
+    }
+
+    fn record<T, E>(&self, consumed: usize, frame: &Result<Option<T>, E>) {
+        if let Ok(Some(_)) = frame {
+            self.context.message_received(consumed);
+        }
+    }
+}
+
+impl<C: Decoder> Decoder for Metered<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<C::Item>, C::Error> {
+        let before = src.len();
+        let frame = self.inner.decode(src);
+        self.record(before.saturating_sub(src.len()), &frame);
+        frame
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<C::Item>, C::Error> {
+        let before = src.len();
+        let frame = self.inner.decode_eof(src);
+        self.record(before.saturating_sub(src.len()), &frame);
+        frame
+    }
+}
+
+impl<I, C: Encoder<I>> Encoder<I> for Metered<C> {
+    type Error = C::Error;
+
+    fn encode(&mut self, item: I, dst: &mut BytesMut) -> Result<(), C::Error> {
+        self.inner.encode(item, dst)?;
+        self.context.message_sent();
+        Ok(())
+    }
+}
+
+/// Reads and writes whole frames over `io`, counting them in `context`.
+pub fn framed<T, C>(io: T, codec: C, context: &ConnectionContext) -> Framed<T, Metered<C>>
+where
+    T: AsyncRead + AsyncWrite,
+{
+    Framed::new(io, Metered::new(codec, context.clone()))
+}
+
+/// Reads whole frames from `io`, counting them in `context`.
+pub fn framed_read<R, C>(io: R, codec: C, context: &ConnectionContext) -> FramedRead<R, Metered<C>>
+where
+    R: AsyncRead,
+    C: Decoder,
+{
+    FramedRead::new(io, Metered::new(codec, context.clone()))
+}
+
+/// Writes whole frames to `io`, counting them in `context`.
+pub fn framed_write<W, C>(
+    io: W,
+    codec: C,
+    context: &ConnectionContext,
+) -> FramedWrite<W, Metered<C>>
+where
+    W: AsyncWrite,
+{
+    FramedWrite::new(io, Metered::new(codec, context.clone()))
+}
+
+fn frame_too_long(max_length: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("frame exceeds maximum length of {} bytes", max_length),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delimiter_split_across_reads() {
+        let mut codec = DelimiterCodec::new(b"\r\n", 16);
+        let mut buf = BytesMut::from(&b"one\r"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.put_slice(b"\ntwo\r\n");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "one");
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "two");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+    }
+
+    #[test]
+    fn delimiter_frame_length() {
+        let mut codec = DelimiterCodec::lines(4);
+        let mut buf = BytesMut::from(&b"abcd\n"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "abcd");
+
+        buf.put_slice(b"abcde\n");
+        let e = codec.decode(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+
+        // Too long even before the delimiter arrives
+        let mut buf = BytesMut::from(&b"abcde"[..]);
+        assert!(DelimiterCodec::lines(4).decode(&mut buf).is_err());
+        assert!(codec.encode(&b"abcde"[..], &mut BytesMut::new()).is_err());
+    }
+
+    #[test]
+    fn delimiter_returns_trailing_frame_at_eof() {
+        let mut codec = DelimiterCodec::lines(16);
+        let mut buf = BytesMut::from(&b"done\npartial"[..]);
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap(), "done");
+        assert_eq!(codec.decode_eof(&mut buf).unwrap().unwrap(), "partial");
+        assert_eq!(codec.decode_eof(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+
+        // One byte short of a delimiter past the limit
+        let mut codec = DelimiterCodec::new(b"\r\n", 4);
+        let mut buf = BytesMut::from(&b"abcde"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        let e = codec.decode_eof(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn delimiter_encode_appends() {
+        let mut buf = BytesMut::new();
+        DelimiterCodec::lines(16)
+            .encode(&b"hi"[..], &mut buf)
+            .unwrap();
+        assert_eq!(buf, "hi\n");
+    }
+
+    #[test]
+    fn fixed_size() {
+        let mut codec = FixedSizeCodec::new(3);
+        let mut buf = BytesMut::from(&b"abcde"[..]);
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "abc");
+        assert_eq!(codec.decode(&mut buf).unwrap(), None);
+        buf.put_u8(b'f');
+        assert_eq!(codec.decode(&mut buf).unwrap().unwrap(), "def");
+
+        let e = codec.encode(&b"ab"[..], &mut BytesMut::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    #[should_panic(expected = "frame size must not be zero")]
+    fn fixed_size_rejects_zero() {
+        FixedSizeCodec::new(0);
+    }
+
+    #[test]
+    fn length_prefixed_round_trip() {
+        for prefix in [LengthPrefix::U8, LengthPrefix::U16, LengthPrefix::U32] {
+            let mut codec = LengthPrefixedCodec::new(prefix, 64);
+            let mut buf = BytesMut::new();
+            codec.encode(&b"hello"[..], &mut buf).unwrap();
+            assert_eq!(buf.len(), prefix.size() + 5);
+
+            let mut partial = buf.split_to(buf.len() - 1);
+            assert_eq!(codec.decode(&mut partial).unwrap(), None);
+            partial.unsplit(buf);
+            assert_eq!(codec.decode(&mut partial).unwrap().unwrap(), "hello");
+            assert!(partial.is_empty());
+        }
+    }
+
+    #[test]
+    fn length_prefixed_too_long() {
+        let mut codec = LengthPrefixedCodec::new(LengthPrefix::U16, 4);
+        let mut buf = BytesMut::from(&[0, 5][..]);
+        assert!(codec.decode(&mut buf).is_err());
+        assert!(codec.encode(&b"hello"[..], &mut BytesMut::new()).is_err());
+
+        // The limit is capped at what the prefix can express
+        let mut codec = LengthPrefixedCodec::new(LengthPrefix::U8, 1000);
+        assert!(codec.encode(&[0; 256][..], &mut BytesMut::new()).is_err());
+    }
+}
@@ -0,0 +1,536 @@
+use std::{
+    collections::HashMap,
+    env,
+    error::Error,
+    fmt, fs, io,
+    num::NonZeroUsize,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use crate::{
+    DatagramLimits, Faults, QueueLimits, Timeouts, TlsFiles,
+    admission::AdmissionLimits,
+    listener::split_addrs,
+    log::{self, Level, LogFormat},
+    proxy_protocol::IpNet,
+    runtime::RuntimeOptions,
+    shutdown::DEFAULT_GRACE_PERIOD,
+};
+
+const ENV_PREFIX: &str = "PROTOHACKERS_";
+const COMMON_SECTION: &str = "server";
+const DEFAULT_ADDR: &str = "0.0.0.0:8000";
+pub(crate) const DEFAULT_STATS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Settings shared by every problem binary.
+///
+/// Values are layered from lowest to highest precedence: built-in defaults,
+/// the `[server]` and `[<problem>]` sections of a TOML file, `PROTOHACKERS_*`
+/// environment variables, then `--key value` command-line flags. Keys the problem
+/// declares are kept in `options` for it to interpret; any other unrecognised key
+/// is an error.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub problem: String,
+    /// Comma-separated listen addresses, each `host:port`, `unix:<path>` or
+    /// `fd:<name>` for a socket passed by systemd.
+    pub addr: String,
+    /// How often stats are printed; zero turns them off.
+    pub stats_interval: Duration,
+    pub grace_period: Duration,
+    pub metrics_addr: Option<String>,
+    pub admin_addr: Option<String>,
+    pub log_level: Level,
+    pub log_format: LogFormat,
+    pub admission: AdmissionLimits,
+    pub timeouts: Timeouts,
+    pub datagram_limits: DatagramLimits,
+    /// Bounds the per-connection queues of handlers that use [`crate::queue`].
+    pub outbound_queue: QueueLimits,
+    pub runtime: RuntimeOptions,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub capture_dir: Option<PathBuf>,
+    pub faults: Faults,
+    /// Networks trusted to send PROXY protocol headers.
+    pub proxy_protocol: Vec<IpNet>,
+    pub options: HashMap<String, String>,
+    declared: Vec<String>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Toml(PathBuf, toml::de::Error),
+    MissingValue(String),
+    UnexpectedArgument(String),
+    UnknownKey(String),
+    InvalidValue { key: String, value: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "failed to read {}: {}", path.display(), e),
+            Self::Toml(path, e) => write!(f, "failed to parse {}: {}", path.display(), e),
+            Self::MissingValue(flag) => write!(f, "missing value for --{}", flag),
+            Self::UnexpectedArgument(arg) => write!(f, "unexpected argument '{}'", arg),
+            Self::UnknownKey(key) => write!(f, "unknown setting '{}'", key),
+            Self::InvalidValue { key, value } => {
+                write!(f, "invalid value for '{}': {}", key, value)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl ServerConfig {
+    pub fn new(problem: &str) -> Self {
+        Self {
+            problem: problem.to_string(),
+            addr: DEFAULT_ADDR.to_string(),
+            stats_interval: DEFAULT_STATS_INTERVAL,
+            grace_period: DEFAULT_GRACE_PERIOD,
+            metrics_addr: None,
+            admin_addr: None,
+            log_level: Level::Info,
+            log_format: LogFormat::Text,
+            admission: AdmissionLimits::default(),
+            timeouts: Timeouts::default(),
+            datagram_limits: DatagramLimits::default(),
+            outbound_queue: QueueLimits::default(),
+            runtime: RuntimeOptions::default(),
+            tls_cert: None,
+            tls_key: None,
+            capture_dir: None,
+            faults: Faults::default(),
+            proxy_protocol: Vec::new(),
+            options: HashMap::new(),
+            declared: Vec::new(),
+        }
+    }
+
+    /// Accepts `keys` as options of the problem rather than rejecting them.
+    pub fn declare_options(&mut self, keys: &[&str]) {
+        self.declared.extend(keys.iter().map(|key| key.to_string()));
+    }
+
+    /// Loads the configuration for `problem` from the process arguments and environment.
+    /// `options` are the problem's own keys.
+    pub fn load(problem: &str, options: &[&str]) -> Result<Self, ConfigError> {
+        Self::from_sources(problem, options, env::args().skip(1), env::vars())
+    }
+
+    pub fn from_sources<A, V>(
+        problem: &str,
+        options: &[&str],
+        args: A,
+        vars: V,
+    ) -> Result<Self, ConfigError>
+    where
+        A: IntoIterator<Item = String>,
+        V: IntoIterator<Item = (String, String)>,
+    {
+        let args = parse_args(args)?;
+        let vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter_map(|(key, value)| {
+                key.strip_prefix(ENV_PREFIX)
+                    .map(|key| (key.to_ascii_lowercase(), value))
+            })
+            .collect();
+
+        let mut config = Self::new(problem);
+        config.declare_options(options);
+
+        let path = args
+            .iter()
+            .chain(vars.iter())
+            .find(|(key, _)| key == "config")
+            .map(|(_, value)| PathBuf::from(value));
+        if let Some(path) = path {
+            config.apply_file(&path)?;
+        }
+
+        for (key, value) in vars.iter().chain(args.iter()) {
+            config.set(key, value)?;
+        }
+
+        // TLS needs both halves of the pair
+        match (&config.tls_cert, &config.tls_key) {
+            (Some(_), None) => return Err(ConfigError::MissingValue("tls-key".to_string())),
+            (None, Some(_)) => return Err(ConfigError::MissingValue("tls-cert".to_string())),
+            _ => {}
+        }
+
+        Ok(config)
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), ConfigError> {
+        match key {
+            "config" => {}
+            "addr" => self.addr = value.to_string(),
+            "stats_interval" => self.stats_interval = parse_secs(key, value)?,
+            "grace_period" => self.grace_period = parse_secs(key, value)?,
+            "metrics_addr" => self.metrics_addr = Some(value.to_string()),
+            "admin_addr" => self.admin_addr = Some(value.to_string()),
+            "log_level" => self.log_level = parse_value(key, value)?,
+            "log_format" => self.log_format = parse_value(key, value)?,
+            "max_connections" => self.admission.max_connections = Some(parse_value(key, value)?),
+            "max_connections_per_ip" => {
+                self.admission.max_connections_per_ip = Some(parse_value(key, value)?)
+            }
+            "idle_timeout" => self.timeouts.idle = Some(parse_secs(key, value)?),
+            "handshake_timeout" => self.timeouts.handshake = Some(parse_secs(key, value)?),
+            "max_session_lifetime" => self.timeouts.lifetime = Some(parse_secs(key, value)?),
+            "accept_rate_per_ip" => {
+                self.admission.accept_rate_per_ip = Some(
+                    parse_value::<f64>(key, value)
+                        .ok()
+                        .filter(|rate| rate.is_finite() && *rate > 0.0)
+                        .ok_or_else(|| ConfigError::InvalidValue {
+                            key: key.to_string(),
+                            value: value.to_string(),
+                        })?,
+                )
+            }
+            "max_datagram_size" => self.datagram_limits.max_size = parse_value(key, value)?,
+            "oversize_datagrams" => self.datagram_limits.oversize = parse_value(key, value)?,
+            "max_in_flight_datagrams" => {
+                self.datagram_limits.max_in_flight = parse_value::<NonZeroUsize>(key, value)?.get()
+            }
+            "outbound_queue" => {
+                self.outbound_queue.capacity = parse_value::<NonZeroUsize>(key, value)?.get()
+            }
+            "outbound_overflow" => self.outbound_queue.overflow = parse_value(key, value)?,
+            "worker_threads" => {
+                self.runtime.worker_threads = Some(parse_value::<NonZeroUsize>(key, value)?.get())
+            }
+            "max_blocking_threads" => {
+                self.runtime.max_blocking_threads =
+                    Some(parse_value::<NonZeroUsize>(key, value)?.get())
+            }
+            "per_core_listeners" => self.runtime.per_core = parse_value(key, value)?,
+            "tls_cert" => self.tls_cert = Some(PathBuf::from(value)),
+            "tls_key" => self.tls_key = Some(PathBuf::from(value)),
+            "capture_dir" => self.capture_dir = Some(PathBuf::from(value)),
+            "proxy_protocol_from" => {
+                self.proxy_protocol = split_addrs(value)
+                    .map(|net| parse_value(key, net))
+                    .collect::<Result<_, _>>()?
+            }
+            "fault_read_chunk" => {
+                self.faults.read_chunk = Some(parse_value::<NonZeroUsize>(key, value)?.get())
+            }
+            "fault_coalesce_writes" => self.faults.coalesce_writes = Some(parse_secs(key, value)?),
+            "fault_latency" => self.faults.latency = Some(parse_secs(key, value)?),
+            "fault_reset_rate" => self.faults.reset_rate = parse_rate(key, value)?,
+            "fault_drop_rate" => self.faults.drop_rate = parse_rate(key, value)?,
+            "fault_duplicate_rate" => self.faults.duplicate_rate = parse_rate(key, value)?,
+            "fault_reorder_rate" => self.faults.reorder_rate = parse_rate(key, value)?,
+            _ if self.declared.iter().any(|declared| declared == key) => {
+                self.options.insert(key.to_string(), value.to_string());
+            }
+            _ => return Err(ConfigError::UnknownKey(key.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Applies the configured log level and format to the global logger.
+    pub fn apply_logging(&self) {
+        log::set_level(self.log_level);
+        log::set_format(self.log_format);
+    }
+
+    /// The certificate and key to serve TCP over TLS with, if both are set.
+    pub fn tls_files(&self) -> Option<TlsFiles> {
+        match (&self.tls_cert, &self.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsFiles::new(cert, key)),
+            _ => None,
+        }
+    }
+
+    pub fn option(&self, key: &str) -> Option<&str> {
+        self.options.get(key).map(String::as_str)
+    }
+
+    pub fn option_or<'a>(&'a self, key: &str, default: &'a str) -> &'a str {
+        self.option(key).unwrap_or(default)
+    }
+
+    fn apply_file(&mut self, path: &Path) -> Result<(), ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e| ConfigError::Toml(path.to_path_buf(), e))?;
+
+        let problem = self.problem.clone();
+        for section in [COMMON_SECTION, problem.as_str()] {
+            match table.get(section) {
+                Some(toml::Value::Table(values)) => {
+                    for (key, value) in values {
+                        self.set(key, &value_to_string(key, value)?)?;
+                    }
+                }
+                Some(_) => {
+                    return Err(ConfigError::InvalidValue {
+                        key: section.to_string(),
+                        value: "expected a table".to_string(),
+                    });
+                }
+                None => {}
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn parse_args<A>(args: A) -> Result<Vec<(String, String)>, ConfigError>
+where
+    A: IntoIterator<Item = String>,
+{
+    let mut parsed = Vec::new();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let Some(flag) = arg.strip_prefix("--") else {
+            return Err(ConfigError::UnexpectedArgument(arg));
+        };
+
+        let (flag, value) = match flag.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), value.to_string()),
+            None => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| ConfigError::MissingValue(flag.to_string()))?;
+                (flag.to_string(), value)
+            }
+        };
+
+        parsed.push((flag.replace('-', "_"), value));
+    }
+
+    Ok(parsed)
+}
+
+fn parse_secs(key: &str, value: &str) -> Result<Duration, ConfigError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| ConfigError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+}
+
+/// A probability from 0 to 1.
+fn parse_rate(key: &str, value: &str) -> Result<f64, ConfigError> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|rate| (0.0..=1.0).contains(rate))
+        .ok_or_else(|| ConfigError::InvalidValue {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue {
+        key: key.to_string(),
+        value: value.to_string(),
+    })
+}
+
+fn value_to_string(key: &str, value: &toml::Value) -> Result<String, ConfigError> {
+    match value {
+        toml::Value::String(s) => Ok(s.clone()),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        other => Err(ConfigError::InvalidValue {
+            key: key.to_string(),
+            value: other.to_string(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(
+        options: &[&str],
+        args: &[&str],
+        vars: &[(&str, &str)],
+    ) -> Result<ServerConfig, ConfigError> {
+        ServerConfig::from_sources(
+            "budget-chat",
+            options,
+            args.iter().map(|arg| arg.to_string()),
+            vars.iter()
+                .map(|(key, value)| (key.to_string(), value.to_string())),
+        )
+    }
+
+    /// Writes `text` to a file of its own for one test.
+    fn config_file(name: &str, text: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("config-{}-{}.toml", std::process::id(), name));
+        fs::write(&path, text).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load(&[], &[], &[]).unwrap();
+        assert_eq!(config.addr, DEFAULT_ADDR);
+        assert_eq!(config.stats_interval, DEFAULT_STATS_INTERVAL);
+        assert_eq!(config.grace_period, DEFAULT_GRACE_PERIOD);
+        assert!(config.tls_files().is_none());
+    }
+
+    #[test]
+    fn precedence() {
+        let path = config_file(
+            "precedence",
+            "[server]\n\
+             addr = \"127.0.0.1:1\"\n\
+             grace_period = 1\n\
+             stats_interval = 1\n\
+             max_connections = 1\n\
+             [budget-chat]\n\
+             grace_period = 2\n\
+             stats_interval = 2\n\
+             max_connections = 2\n\
+             [other-problem]\n\
+             max_connections_per_ip = 99\n",
+        );
+        let path = path.to_str().unwrap();
+        let config = load(
+            &[],
+            &["--config", path, "--max-connections", "4"],
+            &[
+                ("PROTOHACKERS_STATS_INTERVAL", "3"),
+                ("PROTOHACKERS_MAX_CONNECTIONS", "3"),
+                ("UNRELATED", "x"),
+            ],
+        )
+        .unwrap();
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(config.addr, "127.0.0.1:1");
+        assert_eq!(config.grace_period, Duration::from_secs(2));
+        assert_eq!(config.stats_interval, Duration::from_secs(3));
+        assert_eq!(config.admission.max_connections, Some(4));
+        // Other problems' sections don't apply
+        assert_eq!(config.admission.max_connections_per_ip, None);
+    }
+
+    #[test]
+    fn config_path_from_the_environment() {
+        let path = config_file("env", "[server]\naddr = \"127.0.0.1:5\"\n");
+        let config = load(&[], &[], &[("PROTOHACKERS_CONFIG", path.to_str().unwrap())]).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(config.addr, "127.0.0.1:5");
+    }
+
+    #[test]
+    fn flag_forms() {
+        let config = load(&[], &["--addr=127.0.0.1:2", "--log-level", "debug"], &[]).unwrap();
+        assert_eq!(config.addr, "127.0.0.1:2");
+        assert_eq!(config.log_level, Level::Debug);
+
+        assert!(matches!(
+            load(&[], &["--addr"], &[]),
+            Err(ConfigError::MissingValue(flag)) if flag == "addr"
+        ));
+        assert!(matches!(
+            load(&[], &["addr", "x"], &[]),
+            Err(ConfigError::UnexpectedArgument(_))
+        ));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        for result in [
+            load(&[], &["--adr", "127.0.0.1:1"], &[]),
+            load(&[], &[], &[("PROTOHACKERS_BOGUS", "1")]),
+        ] {
+            assert!(matches!(result, Err(ConfigError::UnknownKey(_))));
+        }
+
+        let path = config_file("unknown", "[budget-chat]\nbogus = 1\n");
+        let result = load(&[], &["--config", path.to_str().unwrap()], &[]);
+        fs::remove_file(&path).unwrap();
+        assert!(matches!(result, Err(ConfigError::UnknownKey(key)) if key == "bogus"));
+    }
+
+    #[test]
+    fn declared_options() {
+        let config = load(
+            &["welcome"],
+            &["--welcome", "hi"],
+            &[("PROTOHACKERS_WELCOME", "hello")],
+        )
+        .unwrap();
+        assert_eq!(config.option("welcome"), Some("hi"));
+        assert_eq!(config.option_or("missing", "default"), "default");
+    }
+
+    #[test]
+    fn tls_needs_both_files() {
+        assert!(matches!(
+            load(&[], &["--tls-cert", "cert.pem"], &[]),
+            Err(ConfigError::MissingValue(key)) if key == "tls-key"
+        ));
+        assert!(matches!(
+            load(&[], &["--tls-key", "key.pem"], &[]),
+            Err(ConfigError::MissingValue(key)) if key == "tls-cert"
+        ));
+        let config = load(
+            &[],
+            &["--tls-cert", "cert.pem", "--tls-key", "key.pem"],
+            &[],
+        )
+        .unwrap();
+        assert!(config.tls_files().is_some());
+    }
+
+    #[test]
+    fn accept_rate_per_ip() {
+        let config = load(&[], &["--accept-rate-per-ip", "2.5"], &[]).unwrap();
+        assert_eq!(config.admission.accept_rate_per_ip, Some(2.5));
+
+        for rate in ["0", "-1", "NaN", "inf", "fast"] {
+            assert!(
+                matches!(
+                    load(&[], &["--accept-rate-per-ip", rate], &[]),
+                    Err(ConfigError::InvalidValue { .. })
+                ),
+                "{}",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_values() {
+        for args in [
+            ["--grace-period", "-1"],
+            ["--fault-reset-rate", "1.5"],
+            ["--worker-threads", "0"],
+            ["--proxy-protocol-from", "10.0.0.0/40"],
+        ] {
+            assert!(
+                matches!(load(&[], &args, &[]), Err(ConfigError::InvalidValue { .. })),
+                "{:?}",
+                args
+            );
+        }
+    }
+}
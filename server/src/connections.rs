@@ -0,0 +1,141 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::Address;
+
+/// Live TCP connections, for listing and kicking them from the admin listener.
+/// Clones share the registry, so several servers can report into one.
+#[derive(Debug, Clone, Default)]
+pub struct Connections {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    next_id: AtomicU64,
+    live: Mutex<BTreeMap<u64, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    peer: Address,
+    local: Address,
+    opened: Instant,
+    traffic: Arc<Traffic>,
+    kick: CancellationToken,
+    cancellation: CancellationToken,
+}
+
+/// Counters behind a [`ConnectionContext`](crate::ConnectionContext).
+#[derive(Debug, Default)]
+pub(crate) struct Traffic {
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) messages_received: AtomicU64,
+    pub(crate) messages_sent: AtomicU64,
+}
+
+/// A connection as reported by [`Connections::list`].
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub id: u64,
+    pub peer: Address,
+    /// Address of the listener the client connected to.
+    pub local: Address,
+    pub age: Duration,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Keeps a connection listed until dropped.
+pub(crate) struct Registration {
+    pub(crate) id: u64,
+    pub(crate) traffic: Arc<Traffic>,
+    /// Cancelled when the connection is kicked.
+    pub(crate) kick: CancellationToken,
+    /// Cancelled when the connection is kicked or `closing` is.
+    pub(crate) cancellation: CancellationToken,
+    connections: Connections,
+}
+
+impl Connections {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Assigns the next connection ID and lists the connection under it.
+    pub(crate) fn register(
+        &self,
+        peer: Address,
+        local: Address,
+        closing: &CancellationToken,
+    ) -> Registration {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let traffic = Arc::new(Traffic::default());
+        let kick = CancellationToken::new();
+        let cancellation = closing.child_token();
+
+        self.inner.live.lock().unwrap().insert(
+            id,
+            Entry {
+                peer,
+                local,
+                opened: Instant::now(),
+                traffic: traffic.clone(),
+                kick: kick.clone(),
+                cancellation: cancellation.clone(),
+            },
+        );
+
+        Registration {
+            id,
+            traffic,
+            kick,
+            cancellation,
+            connections: self.clone(),
+        }
+    }
+
+    /// Live connections in ID order.
+    pub fn list(&self) -> Vec<ConnectionInfo> {
+        let live = self.inner.live.lock().unwrap();
+        live.iter()
+            .map(|(&id, entry)| ConnectionInfo {
+                id,
+                peer: entry.peer.clone(),
+                local: entry.local.clone(),
+                age: entry.opened.elapsed(),
+                bytes_received: entry.traffic.bytes_received.load(Ordering::Relaxed),
+                bytes_sent: entry.traffic.bytes_sent.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+
+    /// Cancels the connection's context and makes its reads and writes fail, so its
+    /// handler winds down.
+    /// Returns false if no such connection is open.
+    pub fn kick(&self, id: u64) -> bool {
+        let live = self.inner.live.lock().unwrap();
+        match live.get(&id) {
+            Some(entry) => {
+                entry.kick.cancel();
+                entry.cancellation.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.inner.live.lock().unwrap().remove(&self.id);
+    }
+}
@@ -0,0 +1,149 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFuture};
+
+use crate::{Address, Metrics, connections::Traffic};
+
+/// What a handler knows about the connection it serves, or for UDP the datagram.
+/// Clones share the counters.
+///
+/// TCP bytes are counted by the [`Stream`](crate::Stream) and messages by the codecs in
+/// [`codec`](crate::codec), so handlers using those need not count anything themselves.
+#[derive(Debug, Clone)]
+pub struct ConnectionContext {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    id: u64,
+    peer: Address,
+    local: Address,
+    accepted: Instant,
+    traffic: Arc<Traffic>,
+    metrics: Metrics,
+    cancellation: CancellationToken,
+    kick: CancellationToken,
+}
+
+/// A connection's counters as reported by [`ConnectionContext::stats`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectionStats {
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    pub messages_received: u64,
+    pub messages_sent: u64,
+}
+
+impl ConnectionContext {
+    pub(crate) fn new(
+        id: u64,
+        peer: Address,
+        local: Address,
+        traffic: Arc<Traffic>,
+        metrics: Metrics,
+        cancellation: CancellationToken,
+        kick: CancellationToken,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                id,
+                peer,
+                local,
+                accepted: Instant::now(),
+                traffic,
+                metrics,
+                cancellation,
+                kick,
+            }),
+        }
+    }
+
+    /// Unique among the server's connections, and the ID its log lines carry.
+    pub fn id(&self) -> u64 {
+        self.inner.id
+    }
+
+    pub fn peer(&self) -> &Address {
+        &self.inner.peer
+    }
+
+    /// Address of the listener or socket the client reached.
+    pub fn local(&self) -> &Address {
+        &self.inner.local
+    }
+
+    pub fn accepted(&self) -> Instant {
+        self.inner.accepted
+    }
+
+    pub fn age(&self) -> Duration {
+        self.inner.accepted.elapsed()
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.inner.metrics
+    }
+
+    /// Cancelled when the server starts shutting down or the connection is kicked.
+    /// On shutdown the stream keeps working until the grace period runs out, so
+    /// handlers can finish what they're sending; a kick fails every later read and
+    /// write at once.
+    pub fn cancellation(&self) -> &CancellationToken {
+        &self.inner.cancellation
+    }
+
+    pub fn cancelled(&self) -> WaitForCancellationFuture<'_> {
+        self.inner.cancellation.cancelled()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancellation.is_cancelled()
+    }
+
+    /// Whether the connection was kicked from the admin listener, after which its
+    /// stream no longer works.
+    pub fn is_kicked(&self) -> bool {
+        self.inner.kick.is_cancelled()
+    }
+
+    pub fn bytes_received(&self, count: u64) {
+        self.add(|traffic| &traffic.bytes_received, count);
+        self.inner.metrics.bytes_received(count);
+    }
+
+    pub fn bytes_sent(&self, count: u64) {
+        self.add(|traffic| &traffic.bytes_sent, count);
+        self.inner.metrics.bytes_sent(count);
+    }
+
+    /// Counts a message of `size` bytes, also observed in the message size histogram.
+    pub fn message_received(&self, size: usize) {
+        self.add(|traffic| &traffic.messages_received, 1);
+        self.inner.metrics.message_received(size);
+    }
+
+    pub fn message_sent(&self) {
+        self.add(|traffic| &traffic.messages_sent, 1);
+        self.inner.metrics.message_sent();
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        let traffic = &self.inner.traffic;
+        ConnectionStats {
+            bytes_received: traffic.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: traffic.bytes_sent.load(Ordering::Relaxed),
+            messages_received: traffic.messages_received.load(Ordering::Relaxed),
+            messages_sent: traffic.messages_sent.load(Ordering::Relaxed),
+        }
+    }
+
+    fn add(&self, counter: impl Fn(&Traffic) -> &AtomicU64, count: u64) {
+        counter(&self.inner.traffic).fetch_add(count, Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,142 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Arc, Mutex, RwLock,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+/// Counters identified by a metric name and a single label, such as
+/// `prime_time_requests_total{method="isPrime"}`. Clones share the counts.
+///
+/// Each counter is an atomic looked up under a read lock, so only the first increment
+/// of a new label value takes the write lock.
+#[derive(Debug, Clone, Default)]
+pub struct LabeledCounters {
+    counts: Arc<RwLock<BTreeMap<Key, Arc<AtomicU64>>>>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Key {
+    name: &'static str,
+    label: &'static str,
+    value: &'static str,
+}
+
+/// One labeled counter as reported by [`LabeledCounters::snapshot`].
+#[derive(Debug, Clone)]
+pub struct LabeledCount {
+    pub name: &'static str,
+    pub label: &'static str,
+    pub value: &'static str,
+    pub count: u64,
+}
+
+impl LabeledCounters {
+    pub fn increment(&self, name: &'static str, label: &'static str, value: &'static str) {
+        let key = Key { name, label, value };
+        if let Some(count) = self.counts.read().unwrap().get(&key) {
+            count.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        let mut counts = self.counts.write().unwrap();
+        counts
+            .entry(key)
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// All counters, sorted by name then label value.
+    pub fn snapshot(&self) -> Vec<LabeledCount> {
+        let counts = self.counts.read().unwrap();
+        counts
+            .iter()
+            .map(|(key, count)| LabeledCount {
+                name: key.name,
+                label: key.label,
+                value: key.value,
+                count: count.load(Ordering::Relaxed),
+            })
+            .collect()
+    }
+}
+
+/// Connections and datagrams handled by each per-core shard. Every shard counts into
+/// its own slot, so their accept loops never wait on each other.
+#[derive(Debug, Clone, Default)]
+pub struct ShardCounters {
+    shards: Arc<Mutex<BTreeMap<usize, Arc<ShardCount>>>>,
+}
+
+/// The counts of one shard.
+#[derive(Debug, Default)]
+pub struct ShardCount {
+    pub connections: AtomicU64,
+    pub datagrams: AtomicU64,
+}
+
+impl ShardCounters {
+    /// The counts of shard `index`, to be looked up once when the shard starts.
+    pub fn shard(&self, index: usize) -> Arc<ShardCount> {
+        self.shards
+            .lock()
+            .unwrap()
+            .entry(index)
+            .or_default()
+            .clone()
+    }
+
+    /// Every shard's `(index, connections, datagrams)`, by index.
+    pub fn snapshot(&self) -> Vec<(usize, u64, u64)> {
+        let shards = self.shards.lock().unwrap();
+        shards
+            .iter()
+            .map(|(&index, count)| {
+                (
+                    index,
+                    count.connections.load(Ordering::Relaxed),
+                    count.datagrams.load(Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labeled_counts_are_shared_and_sorted() {
+        let counters = LabeledCounters::default();
+        let threads: Vec<_> = (0..4)
+            .map(|_| {
+                let counters = counters.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        counters.increment("requests", "type", "query");
+                    }
+                    counters.increment("requests", "type", "insert");
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        counters.increment("events", "event", "join");
+
+        let counts: Vec<_> = counters
+            .snapshot()
+            .into_iter()
+            .map(|count| (count.name, count.label, count.value, count.count))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("events", "event", "join", 1),
+                ("requests", "type", "insert", 4),
+                ("requests", "type", "query", 4000),
+            ]
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use bytes::{Bytes, BytesMut};
+use std::{io, net::SocketAddr, ops::Deref, str::FromStr, sync::Arc};
+use tokio::net::UdpSocket;
+
+use crate::{
+    ConnectionContext,
+    capture::{CaptureDir, Direction},
+    log_warning,
+};
+
+// Received datagrams are split off one shared allocation of at least this size
+const RECV_BUFFER_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_DATAGRAM_SIZE: usize = 1024;
+const DEFAULT_MAX_IN_FLIGHT: usize = 1024;
+
+/// Limits applied to every datagram received by a UDP server.
+#[derive(Debug, Clone, Copy)]
+pub struct DatagramLimits {
+    /// Largest datagram passed to handlers intact.
+    pub max_size: usize,
+    pub oversize: OversizePolicy,
+    /// Most handler tasks running at once. Once they are all busy the server stops
+    /// reading, leaving further datagrams to the socket's receive buffer.
+    pub max_in_flight: usize,
+}
+
+/// What happens to a datagram longer than [`DatagramLimits::max_size`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OversizePolicy {
+    /// Discard it and count it in `oversize_datagrams`.
+    #[default]
+    Drop,
+    /// Count it, then hand the first `max_size` bytes to the handler marked as truncated.
+    Deliver,
+}
+
+/// A datagram as seen by a UDP handler.
+#[derive(Debug, Clone)]
+pub struct Datagram {
+    data: Bytes,
+    truncated: bool,
+}
+
+/// The server's socket as seen by a UDP handler, for sending replies. What it sends is
+/// counted in the context of the datagram being handled.
+#[derive(Debug, Clone)]
+pub struct DatagramSocket {
+    socket: Arc<UdpSocket>,
+    capture: Option<CaptureDir>,
+    context: Option<ConnectionContext>,
+}
+
+pub(crate) struct Receiver {
+    buf: BytesMut,
+    max_size: usize,
+}
+
+impl Default for DatagramLimits {
+    fn default() -> Self {
+        Self {
+            max_size: DEFAULT_MAX_DATAGRAM_SIZE,
+            oversize: OversizePolicy::default(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+        }
+    }
+}
+
+impl FromStr for OversizePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "drop" => Ok(Self::Drop),
+            "deliver" => Ok(Self::Deliver),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl Datagram {
+    pub fn new(data: impl Into<Bytes>) -> Self {
+        Self {
+            data: data.into(),
+            truncated: false,
+        }
+    }
+
+    /// True when the datagram was longer than the server's limit and only its start is kept.
+    pub fn is_truncated(&self) -> bool {
+        self.truncated
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.data
+    }
+}
+
+impl Deref for Datagram {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.data
+    }
+}
+
+impl DatagramSocket {
+    pub(crate) fn new(socket: Arc<UdpSocket>, capture: Option<CaptureDir>) -> Self {
+        Self {
+            socket,
+            capture,
+            context: None,
+        }
+    }
+
+    /// A handle counting what it sends in `context`.
+    pub(crate) fn for_context(&self, context: ConnectionContext) -> Self {
+        Self {
+            context: Some(context),
+            ..self.clone()
+        }
+    }
+
+    pub async fn send_to(&self, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+        let sent = self.socket.send_to(buf, target).await?;
+        self.record(target, Direction::Sent, &buf[..sent]);
+        if let Some(context) = &self.context {
+            context.bytes_sent(sent as u64);
+            context.message_sent();
+        }
+        Ok(sent)
+    }
+
+    /// Sends `buf` back to whoever sent the datagram being handled.
+    pub async fn reply(&self, buf: &[u8]) -> io::Result<usize> {
+        let target = self
+            .context
+            .as_ref()
+            .and_then(|context| context.peer().as_inet())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::NotConnected, "no datagram to reply to")
+            })?;
+        self.send_to(buf, target).await
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+
+    /// Appends a datagram to the peer's capture file when capture is enabled.
+    /// Failures are logged and otherwise ignored.
+    pub(crate) fn record(&self, peer: SocketAddr, direction: Direction, data: &[u8]) {
+        let Some(capture) = &self.capture else {
+            return;
+        };
+        let result = self
+            .local_addr()
+            .and_then(|local| capture.udp(local, peer, direction, data));
+        if let Err(e) = result {
+            log_warning!(peer, format!("Traffic capture failed: {}", e));
+        }
+    }
+}
+
+impl Receiver {
+    pub(crate) fn new(max_size: usize) -> Self {
+        Self {
+            buf: BytesMut::with_capacity(RECV_BUFFER_SIZE.max(max_size + 1)),
+            max_size,
+        }
+    }
+
+    /// Receives the next datagram and the number of bytes read from the socket.
+    pub(crate) async fn recv_from(
+        &mut self,
+        socket: &UdpSocket,
+    ) -> io::Result<(Datagram, usize, SocketAddr)> {
+        // Room for one byte past the limit, so oversize datagrams are noticed. Space is
+        // reclaimed once handlers drop earlier datagrams, else a new block is allocated.
+        self.buf.clear();
+        self.buf.reserve(self.max_size + 1);
+
+        let (len, addr) = socket.recv_buf_from(&mut self.buf).await?;
+        let truncated = len > self.max_size;
+        self.buf.truncate(self.max_size);
+
+        let datagram = Datagram {
+            data: self.buf.split().freeze(),
+            truncated,
+        };
+        Ok((datagram, len, addr))
+    }
+}
@@ -0,0 +1,109 @@
+use std::{error::Error, fmt, io};
+
+use crate::log::Level;
+
+/// Why a handler gave up on a connection or datagram.
+#[derive(Debug)]
+pub enum HandlerError {
+    /// The client broke the protocol, e.g. a malformed or oversized message.
+    Protocol(String),
+    Io(io::Error),
+    Timeout(io::Error),
+    /// A server this one depends on failed, such as p05's upstream chat server.
+    Upstream(io::Error),
+    /// A bug or unexpected state on our side.
+    Internal(Box<dyn Error + Send + Sync>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    Protocol,
+    Io,
+    Timeout,
+    Upstream,
+    Internal,
+}
+
+impl HandlerError {
+    pub fn protocol(msg: impl Into<String>) -> Self {
+        Self::Protocol(msg.into())
+    }
+
+    pub fn internal(err: impl Into<Box<dyn Error + Send + Sync>>) -> Self {
+        Self::Internal(err.into())
+    }
+
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Self::Protocol(_) => ErrorCategory::Protocol,
+            Self::Io(_) => ErrorCategory::Io,
+            Self::Timeout(_) => ErrorCategory::Timeout,
+            Self::Upstream(_) => ErrorCategory::Upstream,
+            Self::Internal(_) => ErrorCategory::Internal,
+        }
+    }
+}
+
+impl ErrorCategory {
+    pub const ALL: [Self; 5] = [
+        Self::Protocol,
+        Self::Io,
+        Self::Timeout,
+        Self::Upstream,
+        Self::Internal,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Protocol => "protocol",
+            Self::Io => "io",
+            Self::Timeout => "timeout",
+            Self::Upstream => "upstream",
+            Self::Internal => "internal",
+        }
+    }
+
+    /// Level errors of this category are logged at. Misbehaving clients and dropped
+    /// connections are routine; failures on our side or upstream are not.
+    pub fn level(self) -> Level {
+        match self {
+            Self::Timeout => Level::Info,
+            Self::Protocol | Self::Io => Level::Warning,
+            Self::Upstream | Self::Internal => Level::Error,
+        }
+    }
+}
+
+impl fmt::Display for HandlerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Protocol(msg) => write!(f, "protocol error: {}", msg),
+            Self::Io(e) => write!(f, "I/O error: {}", e),
+            Self::Timeout(e) => write!(f, "timeout: {}", e),
+            Self::Upstream(e) => write!(f, "upstream error: {}", e),
+            Self::Internal(e) => write!(f, "internal error: {}", e),
+        }
+    }
+}
+
+impl Error for HandlerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Protocol(_) => None,
+            Self::Io(e) | Self::Timeout(e) | Self::Upstream(e) => Some(e),
+            Self::Internal(e) => Some(e.as_ref()),
+        }
+    }
+}
+
+/// Timeouts from [`Stream`](crate::Stream) and invalid data from the codecs keep
+/// their meaning; anything else is treated as a transport failure.
+impl From<io::Error> for HandlerError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::TimedOut => Self::Timeout(e),
+            io::ErrorKind::InvalidData => Self::Protocol(e.to_string()),
+            _ => Self::Io(e),
+        }
+    }
+}
@@ -0,0 +1,329 @@
+use std::{fmt::Write as _, io, sync::atomic::Ordering, time::Duration};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+    time::timeout,
+};
+
+use crate::{ErrorCategory, Histogram, Metrics, Shutdown, log_error, log_info};
+
+const MAX_REQUEST_SIZE: usize = 8192;
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// How long a scraper gets to send its whole request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Serves `/metrics` in the Prometheus text format and `/healthz` until aborted.
+pub(crate) fn spawn(listener: TcpListener, metrics: Metrics, shutdown: Shutdown) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        if let Ok(addr) = listener.local_addr() {
+            log_info!(addr, "Metrics exporter started");
+        }
+
+        loop {
+            let (stream, client_addr) = match listener.accept().await {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    log_error!("exporter", format!("Accept error: {}", e));
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+
+            let metrics = metrics.clone();
+            let shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = respond(stream, &metrics, &shutdown).await {
+                    log_error!(client_addr, format!("Exporter error: {}", e));
+                }
+            });
+        }
+    })
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics, shutdown: &Shutdown) -> io::Result<()> {
+    let request = match timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(Ok(Some(request))) => request,
+        Ok(Ok(None)) => return Ok(()),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return write_response(&mut stream, "408 Request Timeout", "").await,
+    };
+    if request.len() > MAX_REQUEST_SIZE {
+        return write_response(&mut stream, "431 Request Header Fields Too Large", "").await;
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut parts = request.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    match (method, path) {
+        ("GET", "/metrics") => write_response(&mut stream, "200 OK", &render(metrics)).await,
+        ("GET", "/healthz") if shutdown.is_triggered() => {
+            write_response(&mut stream, "503 Service Unavailable", "shutting down\n").await
+        }
+        ("GET", "/healthz") => write_response(&mut stream, "200 OK", "ok\n").await,
+        ("GET", _) => write_response(&mut stream, "404 Not Found", "not found\n").await,
+        _ => write_response(&mut stream, "405 Method Not Allowed", "").await,
+    }
+}
+
+/// Reads up to the end of the request headers, or past `MAX_REQUEST_SIZE`. `None` means
+/// the client went away first.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() <= MAX_REQUEST_SIZE {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(request))
+}
+
+async fn write_response(stream: &mut TcpStream, status: &str, body: &str) -> io::Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Renders the metrics in the Prometheus text exposition format.
+pub fn render(metrics: &Metrics) -> String {
+    let mut out = String::new();
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: String| {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} {}", name, kind);
+        let _ = writeln!(out, "{} {}", name, value);
+    };
+
+    metric(
+        "connections_total",
+        "counter",
+        "Connections accepted since startup.",
+        metrics
+            .connections_total
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "connections_active",
+        "gauge",
+        "Connections currently open.",
+        metrics
+            .connections_active
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "bytes_received",
+        "counter",
+        "Bytes read from clients.",
+        metrics.bytes_received.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "bytes_sent",
+        "counter",
+        "Bytes written to clients.",
+        metrics.bytes_sent.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "messages_sent_total",
+        "counter",
+        "Messages or datagrams sent to clients.",
+        metrics.messages_sent.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "errors_total",
+        "counter",
+        "Handler and transport errors.",
+        metrics.errors_total.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "connections_rejected_total",
+        "counter",
+        "Connections refused by admission control.",
+        metrics
+            .connections_rejected
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "timeouts_total",
+        "counter",
+        "Connections closed by idle, handshake or lifetime timeouts.",
+        metrics.timeouts_total.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "tls_handshake_failures_total",
+        "counter",
+        "TLS connections dropped because the handshake failed.",
+        metrics
+            .tls_handshake_failures
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "proxy_header_failures_total",
+        "counter",
+        "Connections from trusted proxies dropped for a missing or malformed PROXY header.",
+        metrics
+            .proxy_header_failures
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "oversize_datagrams_total",
+        "counter",
+        "UDP datagrams longer than the configured maximum size.",
+        metrics
+            .oversize_datagrams
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "outbound_queue_depth",
+        "gauge",
+        "Messages waiting in outbound queues.",
+        metrics.outbound_queued.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "outbound_dropped_total",
+        "counter",
+        "Messages discarded because their outbound queue was full.",
+        metrics.outbound_dropped.load(Ordering::Relaxed).to_string(),
+    );
+    metric(
+        "slow_consumer_disconnects_total",
+        "counter",
+        "Clients disconnected for letting their outbound queue fill up.",
+        metrics
+            .slow_consumer_disconnects
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "datagram_tasks_active",
+        "gauge",
+        "UDP handler tasks currently running.",
+        metrics
+            .datagram_tasks_active
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "datagram_throttles_total",
+        "counter",
+        "Times a UDP server stopped reading because every handler slot was busy.",
+        metrics
+            .datagram_throttles
+            .load(Ordering::Relaxed)
+            .to_string(),
+    );
+    metric(
+        "uptime_seconds",
+        "gauge",
+        "Seconds since the server started.",
+        metrics.uptime().as_secs_f64().to_string(),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP handler_errors_total Handler errors by category."
+    );
+    let _ = writeln!(out, "# TYPE handler_errors_total counter");
+    for category in ErrorCategory::ALL {
+        let _ = writeln!(
+            out,
+            "handler_errors_total{{category=\"{}\"}} {}",
+            category.as_str(),
+            metrics.errors_by_category[category as usize].load(Ordering::Relaxed)
+        );
+    }
+
+    render_histogram(
+        &mut out,
+        "request_latency_seconds",
+        "Time taken to answer a request.",
+        &metrics.request_latency,
+    );
+    render_histogram(
+        &mut out,
+        "message_size_bytes",
+        "Size of each message or datagram received.",
+        &metrics.message_size,
+    );
+    render_histogram(
+        &mut out,
+        "connection_duration_seconds",
+        "How long TCP connections stayed open.",
+        &metrics.connection_duration,
+    );
+
+    let mut previous = None;
+    for counter in metrics.labeled.snapshot() {
+        if previous != Some(counter.name) {
+            let _ = writeln!(out, "# TYPE {} counter", counter.name);
+            previous = Some(counter.name);
+        }
+        let _ = writeln!(
+            out,
+            "{}{{{}=\"{}\"}} {}",
+            counter.name,
+            counter.label,
+            escape_label(counter.value),
+            counter.count
+        );
+    }
+
+    let shards = metrics.shards.snapshot();
+    if !shards.is_empty() {
+        let _ = writeln!(out, "# TYPE shard_connections_total counter");
+        for (index, connections, _) in &shards {
+            let _ = writeln!(
+                out,
+                "shard_connections_total{{shard=\"{}\"}} {}",
+                index, connections
+            );
+        }
+        let _ = writeln!(out, "# TYPE shard_datagrams_total counter");
+        for (index, _, datagrams) in &shards {
+            let _ = writeln!(
+                out,
+                "shard_datagrams_total{{shard=\"{}\"}} {}",
+                index, datagrams
+            );
+        }
+    }
+
+    out
+}
+
+fn render_histogram(out: &mut String, name: &str, help: &str, histogram: &Histogram) {
+    let snapshot = histogram.snapshot();
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+
+    for (bound, count) in snapshot.bounds.iter().zip(&snapshot.cumulative) {
+        let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, count);
+    }
+    let _ = writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, snapshot.count);
+    let _ = writeln!(out, "{}_sum {}", name, snapshot.sum);
+    let _ = writeln!(out, "{}_count {}", name, snapshot.count);
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,421 @@
+//! Opt-in fault injection for exercising handlers against a hostile network.
+//!
+//! TCP streams can have their reads split into tiny chunks and delayed, their writes
+//! held back and sent together, and the connection reset at random. UDP datagrams can
+//! be dropped, handled twice, delayed or overtaken by later ones. Every injected fault
+//! is counted in `faults_injected_total{kind}`.
+
+use std::{
+    collections::hash_map::RandomState,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    io,
+    pin::Pin,
+    task::{Context, Poll, ready},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Sleep, sleep},
+};
+
+use crate::Metrics;
+
+const INJECTED: &str = "faults_injected_total";
+const READ_AHEAD: usize = 8 * 1024;
+// Held writes are sent early once this many bytes pile up
+const MAX_HELD: usize = 64 * 1024;
+// How long a reordered datagram waits on top of any latency
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Faults to inject, all off by default. Rates are probabilities from 0 to 1.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    /// Most bytes a single TCP read returns; 1 delivers data byte by byte.
+    pub read_chunk: Option<usize>,
+    /// Holds TCP writes for this long so consecutive writes reach the client together.
+    /// Whatever is still held when the handler returns is sent before the connection
+    /// closes.
+    pub coalesce_writes: Option<Duration>,
+    /// Longest random delay before TCP data or a UDP datagram is delivered.
+    pub latency: Option<Duration>,
+    /// Chance that a TCP read or write resets the connection instead.
+    pub reset_rate: f64,
+    /// Chance that a UDP datagram never reaches the handler.
+    pub drop_rate: f64,
+    /// Chance that a UDP datagram is handled twice.
+    pub duplicate_rate: f64,
+    /// Chance that a UDP datagram is held back so later ones overtake it.
+    pub reorder_rate: f64,
+}
+
+/// What happens to one received datagram.
+pub(crate) enum Fate {
+    Drop,
+    Deliver { copies: usize, delay: Duration },
+}
+
+/// A cheap random source; xorshift is plenty for picking faults.
+pub(crate) struct Dice(u64);
+
+/// Wraps a stream to inject the TCP faults of a [`Faults`].
+pub(crate) struct FaultyStream<T> {
+    inner: T,
+    faults: Faults,
+    dice: Dice,
+    metrics: Metrics,
+    read_delay: Option<Pin<Box<Sleep>>>,
+    scratch: Vec<u8>,
+    arrived: Vec<u8>,
+    held: Vec<u8>,
+    send_held: Option<Pin<Box<Sleep>>>,
+    reset: bool,
+}
+
+/// Streams that can be torn down so the peer sees a reset rather than a close.
+pub(crate) trait Abort {
+    fn abort(&self);
+}
+
+impl Faults {
+    pub fn is_enabled(&self) -> bool {
+        self.is_tcp_enabled()
+            || self.drop_rate > 0.0
+            || self.duplicate_rate > 0.0
+            || self.reorder_rate > 0.0
+    }
+
+    pub(crate) fn is_tcp_enabled(&self) -> bool {
+        self.read_chunk.is_some()
+            || self.coalesce_writes.is_some()
+            || self.latency.is_some()
+            || self.reset_rate > 0.0
+    }
+
+    pub(crate) fn datagram_fate(&self, dice: &mut Dice, metrics: &Metrics) -> Fate {
+        if dice.roll(self.drop_rate) {
+            metrics.increment(INJECTED, "kind", "drop");
+            return Fate::Drop;
+        }
+
+        let mut copies = 1;
+        if dice.roll(self.duplicate_rate) {
+            metrics.increment(INJECTED, "kind", "duplicate");
+            copies = 2;
+        }
+        let mut delay = self.latency.map_or(Duration::ZERO, |max| dice.below(max));
+        if dice.roll(self.reorder_rate) {
+            metrics.increment(INJECTED, "kind", "reorder");
+            delay += REORDER_DELAY;
+        }
+        Fate::Deliver { copies, delay }
+    }
+}
+
+impl Dice {
+    pub(crate) fn new() -> Self {
+        // A fresh RandomState is seeded differently every time
+        Self::seeded(RandomState::new().build_hasher().finish())
+    }
+
+    /// Rolls the same numbers for the same seed.
+    pub(crate) fn seeded(seed: u64) -> Self {
+        // xorshift never leaves zero
+        Self(seed | 1)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn fraction(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn roll(&mut self, rate: f64) -> bool {
+        rate > 0.0 && self.fraction() < rate
+    }
+
+    fn below(&mut self, max: Duration) -> Duration {
+        max.mul_f64(self.fraction())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Abort + Unpin> FaultyStream<T> {
+    pub(crate) fn new(inner: T, faults: Faults, metrics: Metrics) -> Self {
+        Self {
+            inner,
+            faults,
+            dice: Dice::new(),
+            metrics,
+            read_delay: None,
+            scratch: Vec::new(),
+            arrived: Vec::new(),
+            held: Vec::new(),
+            send_held: None,
+            reset: false,
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Rolls for a reset, which then fails every later read and write too.
+    fn check_reset(&mut self) -> io::Result<()> {
+        if !self.reset && self.dice.roll(self.faults.reset_rate) {
+            self.reset = true;
+            self.inner.abort();
+            self.metrics.increment(INJECTED, "kind", "reset");
+        }
+        if self.reset {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "injected connection reset",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Sends held writes once their window has passed.
+    fn poll_due(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some(send_held) = self.send_held.as_mut()
+            && send_held.as_mut().poll(cx).is_ready()
+            && let Poll::Ready(Err(e)) = self.poll_send_held(cx)
+        {
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn poll_send_held(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.held.is_empty() {
+            let written = ready!(Pin::new(&mut self.inner).poll_write(cx, &self.held))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.held.drain(..written);
+        }
+        self.send_held = None;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Abort + Unpin> AsyncRead for FaultyStream<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.reset {
+            return Poll::Ready(this.check_reset());
+        }
+        // Handlers waiting on a read would otherwise never see their held writes go out
+        this.poll_due(cx)?;
+
+        // Data is read ahead into `arrived`, then handed out late and in small pieces
+        if this.arrived.is_empty() {
+            this.scratch.resize(READ_AHEAD, 0);
+            let mut ahead = ReadBuf::new(&mut this.scratch);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut ahead))?;
+            if ahead.filled().is_empty() {
+                return Poll::Ready(this.check_reset());
+            }
+            this.arrived.extend_from_slice(ahead.filled());
+            if let Some(max) = this.faults.latency {
+                this.read_delay = Some(Box::pin(sleep(this.dice.below(max))));
+            }
+        }
+        if let Some(delay) = this.read_delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            this.read_delay = None;
+        }
+
+        let chunk = this.faults.read_chunk.unwrap_or(usize::MAX);
+        let len = this.arrived.len().min(chunk).min(buf.remaining());
+        buf.put_slice(&this.arrived[..len]);
+        this.arrived.drain(..len);
+        Poll::Ready(this.check_reset())
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Abort + Unpin> AsyncWrite for FaultyStream<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.check_reset()?;
+
+        let Some(window) = this.faults.coalesce_writes else {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        };
+        this.poll_due(cx)?;
+        if this.held.len() >= MAX_HELD {
+            ready!(this.poll_send_held(cx))?;
+        }
+        if this.send_held.is_none() {
+            this.send_held = Some(Box::pin(sleep(window)));
+        }
+        this.held.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // Flushing doesn't end the window, or framed writers would never coalesce
+        let this = self.get_mut();
+        this.poll_due(cx)?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_held(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream, duplex};
+
+    impl Abort for DuplexStream {
+        fn abort(&self) {}
+    }
+
+    const SEED: u64 = 0x5eed;
+
+    fn faulty(faults: Faults) -> (FaultyStream<DuplexStream>, DuplexStream, Metrics) {
+        let (inner, peer) = duplex(1024);
+        let metrics = Metrics::new();
+        let mut stream = FaultyStream::new(inner, faults, metrics.clone());
+        stream.dice = Dice::seeded(SEED);
+        (stream, peer, metrics)
+    }
+
+    fn injected(metrics: &Metrics, kind: &str) -> u64 {
+        metrics
+            .labeled
+            .snapshot()
+            .iter()
+            .find(|count| count.name == INJECTED && count.value == kind)
+            .map_or(0, |count| count.count)
+    }
+
+    #[tokio::test]
+    async fn reads_are_chunked() {
+        let (mut stream, mut peer, _) = faulty(Faults {
+            read_chunk: Some(3),
+            ..Faults::default()
+        });
+        peer.write_all(b"hello world").await.unwrap();
+        drop(peer);
+
+        let mut buf = [0; 64];
+        let mut reads = Vec::new();
+        loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            reads.push(buf[..n].to_vec());
+        }
+        assert_eq!(reads, [&b"hel"[..], b"lo ", b"wor", b"ld"]);
+    }
+
+    #[tokio::test]
+    async fn flushed_writes_are_held_for_the_window() {
+        let window = Duration::from_millis(50);
+        let (mut stream, mut peer, _) = faulty(Faults {
+            coalesce_writes: Some(window),
+            ..Faults::default()
+        });
+
+        for part in [&b"a"[..], b"b", b"c"] {
+            stream.write_all(part).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+        let mut buf = [0; 64];
+        let early = tokio::time::timeout(window / 2, peer.read(&mut buf)).await;
+        assert!(early.is_err(), "sent before the window passed");
+
+        // A handler blocked on a read sends them once the window is over
+        let mut unused = [0; 1];
+        tokio::select! {
+            _ = stream.read(&mut unused) => panic!("the peer sent nothing"),
+            n = peer.read(&mut buf) => assert_eq!(&buf[..n.unwrap()], b"abc"),
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown_sends_held_writes() {
+        let (mut stream, mut peer, _) = faulty(Faults {
+            coalesce_writes: Some(Duration::from_secs(60)),
+            ..Faults::default()
+        });
+
+        stream.write_all(b"one ").await.unwrap();
+        stream.write_all(b"two").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"one two");
+    }
+
+    #[tokio::test]
+    async fn resets_are_seeded_and_final() {
+        // The same seed resets at the same write every time
+        let mut resets = Vec::new();
+        for _ in 0..2 {
+            let (mut stream, _peer, metrics) = faulty(Faults {
+                reset_rate: 0.2,
+                ..Faults::default()
+            });
+            let mut writes = 0;
+            while stream.write_all(b"x").await.is_ok() {
+                writes += 1;
+            }
+            let mut buf = [0; 1];
+            let err = stream.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert_eq!(injected(&metrics, "reset"), 1);
+            resets.push(writes);
+        }
+        assert_eq!(resets[0], resets[1]);
+    }
+
+    #[test]
+    fn datagram_fates_follow_the_seed() {
+        let faults = Faults {
+            drop_rate: 0.3,
+            duplicate_rate: 0.3,
+            ..Faults::default()
+        };
+        let metrics = Metrics::new();
+        let fates = |dice: &mut Dice| -> Vec<Option<usize>> {
+            (0..100)
+                .map(|_| match faults.datagram_fate(dice, &metrics) {
+                    Fate::Drop => None,
+                    Fate::Deliver { copies, .. } => Some(copies),
+                })
+                .collect()
+        };
+
+        let first = fates(&mut Dice::seeded(SEED));
+        assert_eq!(first, fates(&mut Dice::seeded(SEED)));
+        assert!(first.contains(&None));
+        assert!(first.contains(&Some(1)));
+        assert!(first.contains(&Some(2)));
+        assert_eq!(
+            injected(&metrics, "drop"),
+            2 * first.iter().filter(|fate| fate.is_none()).count() as u64
+        );
+    }
+}
@@ -0,0 +1,63 @@
+use std::future::Future;
+
+use crate::{ConnectionContext, Datagram, DatagramSocket, HandlerError, Stream};
+
+/// Serves one TCP connection. Implement this on a type holding shared state, or pass a
+/// plain `async fn(Stream, ConnectionContext)` which implements it automatically.
+pub trait TcpHandler: Send + Sync + 'static {
+    fn handle(
+        &self,
+        stream: Stream,
+        context: ConnectionContext,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send;
+
+    /// Called once after the server stops and its connections have drained.
+    fn shutdown(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+/// Serves one UDP datagram, whose sender is the context's peer. Implemented
+/// automatically for `async fn(Datagram, DatagramSocket, ConnectionContext)`.
+pub trait UdpHandler: Send + Sync + 'static {
+    fn handle(
+        &self,
+        packet: Datagram,
+        socket: DatagramSocket,
+        context: ConnectionContext,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send;
+
+    /// Called once after the server stops and in-flight datagrams have drained.
+    fn shutdown(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+}
+
+impl<F, Fut> TcpHandler for F
+where
+    F: Fn(Stream, ConnectionContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), HandlerError>> + Send,
+{
+    fn handle(
+        &self,
+        stream: Stream,
+        context: ConnectionContext,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        self(stream, context)
+    }
+}
+
+impl<F, Fut> UdpHandler for F
+where
+    F: Fn(Datagram, DatagramSocket, ConnectionContext) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<(), HandlerError>> + Send,
+{
+    fn handle(
+        &self,
+        packet: Datagram,
+        socket: DatagramSocket,
+        context: ConnectionContext,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        self(packet, socket, context)
+    }
+}
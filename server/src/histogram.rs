@@ -0,0 +1,110 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// Upper bounds in seconds, for latencies and connection durations.
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0, 60.0, 300.0,
+];
+
+/// Upper bounds in bytes, for message sizes.
+pub const SIZE_BUCKETS: &[f64] = &[
+    16.0, 64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+];
+
+/// Counts observations into fixed buckets, Prometheus style. Clones share the counts.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    bounds: &'static [f64],
+    // One per bound plus a final +Inf bucket, not cumulative
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    // f64 bits
+    sum: AtomicU64,
+}
+
+/// A point-in-time copy of a [`Histogram`].
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub bounds: &'static [f64],
+    /// Observations at or below each bound, then the total for +Inf.
+    pub cumulative: Vec<u64>,
+    pub count: u64,
+    pub sum: f64,
+}
+
+impl Histogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                bounds,
+                buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+                count: AtomicU64::new(0),
+                sum: AtomicU64::new(0f64.to_bits()),
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let inner = &self.inner;
+        let index = inner.bounds.partition_point(|&bound| bound < value);
+        inner.buckets[index].fetch_add(1, Ordering::Relaxed);
+        inner.count.fetch_add(1, Ordering::Relaxed);
+
+        let _ = inner
+            .sum
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                Some((f64::from_bits(bits) + value).to_bits())
+            });
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let mut total = 0;
+        let cumulative = self
+            .inner
+            .buckets
+            .iter()
+            .map(|bucket| {
+                total += bucket.load(Ordering::Relaxed);
+                total
+            })
+            .collect();
+
+        Snapshot {
+            bounds: self.inner.bounds,
+            cumulative,
+            count: total,
+            sum: f64::from_bits(self.inner.sum.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Snapshot {
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum / self.count as f64)
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile, or infinity past the last bound.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let index = self.cumulative.partition_point(|&seen| seen < rank);
+        Some(self.bounds.get(index).copied().unwrap_or(f64::INFINITY))
+    }
+}
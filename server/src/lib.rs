@@ -1,13 +1,82 @@
 use std::{
     error::Error,
-    net::SocketAddr,
+    fmt,
+    future::pending,
+    io,
     sync::{
         Arc,
         atomic::{AtomicU64, Ordering},
     },
     time::{Duration, Instant},
 };
-use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::{
+    net::UdpSocket,
+    sync::Semaphore,
+    task::{JoinHandle, JoinSet},
+    time::sleep,
+};
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    admission::Admission,
+    builder::ServeOptions,
+    capture::{CaptureDir, Direction},
+    datagram::Receiver,
+    faults::{Dice, Fate},
+    histogram::{DURATION_BUCKETS, SIZE_BUCKETS},
+    listener::{Incoming, Listener, accept_any},
+    proxy_protocol::IpNet,
+};
+
+pub mod activation;
+pub mod address;
+pub mod admin;
+pub mod admission;
+pub mod builder;
+pub mod capture;
+pub mod codec;
+pub mod config;
+pub mod connections;
+pub mod context;
+pub mod counters;
+pub mod datagram;
+pub mod error;
+pub mod exporter;
+pub mod faults;
+pub mod handler;
+pub mod histogram;
+pub mod listener;
+pub mod log;
+pub mod proxy_protocol;
+pub mod queue;
+pub mod runtime;
+pub mod shutdown;
+pub mod stream;
+pub mod tls;
+
+pub use address::Address;
+pub use admission::AdmissionLimits;
+pub use builder::{ServerBuilder, ServerHandle};
+pub use config::{ConfigError, ServerConfig};
+pub use connections::{ConnectionInfo, Connections};
+pub use context::{ConnectionContext, ConnectionStats};
+pub use counters::{LabeledCounters, ShardCounters};
+pub use datagram::{Datagram, DatagramLimits, DatagramSocket, OversizePolicy};
+pub use error::{ErrorCategory, HandlerError};
+pub use faults::Faults;
+pub use handler::{TcpHandler, UdpHandler};
+pub use histogram::Histogram;
+pub use queue::{OverflowPolicy, QueueLimits};
+pub use shutdown::Shutdown;
+pub use stream::{Stream, Timeouts};
+pub use tls::TlsFiles;
+
+// Pause after a failed accept, e.g. out of file descriptors, before trying again
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+// How long a trusted balancer gets to send its PROXY header when no handshake or
+// idle timeout is set
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Clone)]
 pub struct Metrics {
@@ -15,10 +84,46 @@ pub struct Metrics {
     pub connections_active: Arc<AtomicU64>,
     pub bytes_received: Arc<AtomicU64>,
     pub bytes_sent: Arc<AtomicU64>,
+    /// Messages or datagrams sent to clients.
+    pub messages_sent: Arc<AtomicU64>,
     pub errors_total: Arc<AtomicU64>,
+    /// Handler errors split by [`ErrorCategory`], indexed by `category as usize`.
+    pub errors_by_category: Arc<[AtomicU64; ErrorCategory::ALL.len()]>,
+    pub connections_rejected: Arc<AtomicU64>,
+    pub timeouts_total: Arc<AtomicU64>,
+    pub tls_handshake_failures: Arc<AtomicU64>,
+    /// Connections from trusted proxies dropped for a missing or malformed PROXY header.
+    pub proxy_header_failures: Arc<AtomicU64>,
+    pub oversize_datagrams: Arc<AtomicU64>,
+    /// Messages waiting in outbound queues.
+    pub outbound_queued: Arc<AtomicU64>,
+    /// Messages discarded because their outbound queue was full.
+    pub outbound_dropped: Arc<AtomicU64>,
+    /// Clients disconnected for letting their outbound queue fill up.
+    pub slow_consumer_disconnects: Arc<AtomicU64>,
+    /// UDP handler tasks currently running.
+    pub datagram_tasks_active: Arc<AtomicU64>,
+    /// Times a UDP server stopped reading because every handler slot was busy.
+    pub datagram_throttles: Arc<AtomicU64>,
+    /// Time taken to answer a request, in seconds.
+    pub request_latency: Histogram,
+    /// Size of each message or datagram received, in bytes.
+    pub message_size: Histogram,
+    /// How long TCP connections stayed open, in seconds.
+    pub connection_duration: Histogram,
+    /// Per-problem counters, e.g. requests by method.
+    pub labeled: LabeledCounters,
+    /// What each per-core shard handled.
+    pub shards: ShardCounters,
     pub start_time: Instant,
 }
 
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Metrics {
     pub fn new() -> Self {
         Self {
@@ -26,7 +131,24 @@ impl Metrics {
             connections_active: Arc::new(AtomicU64::new(0)),
             bytes_received: Arc::new(AtomicU64::new(0)),
             bytes_sent: Arc::new(AtomicU64::new(0)),
+            messages_sent: Arc::new(AtomicU64::new(0)),
             errors_total: Arc::new(AtomicU64::new(0)),
+            errors_by_category: Arc::new(Default::default()),
+            connections_rejected: Arc::new(AtomicU64::new(0)),
+            timeouts_total: Arc::new(AtomicU64::new(0)),
+            tls_handshake_failures: Arc::new(AtomicU64::new(0)),
+            proxy_header_failures: Arc::new(AtomicU64::new(0)),
+            oversize_datagrams: Arc::new(AtomicU64::new(0)),
+            outbound_queued: Arc::new(AtomicU64::new(0)),
+            outbound_dropped: Arc::new(AtomicU64::new(0)),
+            slow_consumer_disconnects: Arc::new(AtomicU64::new(0)),
+            datagram_tasks_active: Arc::new(AtomicU64::new(0)),
+            datagram_throttles: Arc::new(AtomicU64::new(0)),
+            request_latency: Histogram::new(DURATION_BUCKETS),
+            message_size: Histogram::new(SIZE_BUCKETS),
+            connection_duration: Histogram::new(DURATION_BUCKETS),
+            labeled: LabeledCounters::default(),
+            shards: ShardCounters::default(),
             start_time: Instant::now(),
         }
     }
@@ -52,6 +174,70 @@ impl Metrics {
         self.errors_total.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Counts an error in `errors_total` and in its category.
+    pub fn error_in(&self, category: ErrorCategory) {
+        self.errors_total.fetch_add(1, Ordering::Relaxed);
+        self.errors_by_category[category as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn connection_rejected(&self) {
+        self.connections_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn timeout_occurred(&self) {
+        self.timeouts_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn tls_handshake_failed(&self) {
+        self.tls_handshake_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn proxy_header_failed(&self) {
+        self.proxy_header_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn datagram_oversize(&self) {
+        self.oversize_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn outbound_queued(&self) {
+        self.outbound_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn outbound_dequeued(&self, count: u64) {
+        self.outbound_queued.fetch_sub(count, Ordering::Relaxed);
+    }
+
+    pub fn outbound_dropped(&self) {
+        self.outbound_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn slow_consumer_disconnected(&self) {
+        self.slow_consumer_disconnects
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn datagram_throttled(&self) {
+        self.datagram_throttles.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn request_completed(&self, latency: Duration) {
+        self.request_latency.observe_duration(latency);
+    }
+
+    pub fn message_received(&self, size: usize) {
+        self.message_size.observe(size as f64);
+    }
+
+    pub fn message_sent(&self) {
+        self.messages_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts one event under `name{label="value"}`.
+    pub fn increment(&self, name: &'static str, label: &'static str, value: &'static str) {
+        self.labeled.increment(name, label, value);
+    }
+
     pub fn uptime(&self) -> std::time::Duration {
         self.start_time.elapsed()
     }
@@ -72,142 +258,683 @@ impl Metrics {
             self.bytes_received.load(Ordering::Relaxed)
         );
         println!("Bytes sent: {}", self.bytes_sent.load(Ordering::Relaxed));
+        println!(
+            "Messages sent: {}",
+            self.messages_sent.load(Ordering::Relaxed)
+        );
         println!(
             "Total errors: {}",
             self.errors_total.load(Ordering::Relaxed)
         );
+        let by_category: Vec<String> = ErrorCategory::ALL
+            .iter()
+            .map(|&category| {
+                let count = self.errors_by_category[category as usize].load(Ordering::Relaxed);
+                format!("{}={}", category.as_str(), count)
+            })
+            .collect();
+        println!("Errors by category: {}", by_category.join(", "));
+        println!(
+            "Rejected connections: {}",
+            self.connections_rejected.load(Ordering::Relaxed)
+        );
+        println!(
+            "Total timeouts: {}",
+            self.timeouts_total.load(Ordering::Relaxed)
+        );
+        println!(
+            "TLS handshake failures: {}",
+            self.tls_handshake_failures.load(Ordering::Relaxed)
+        );
+        println!(
+            "PROXY header failures: {}",
+            self.proxy_header_failures.load(Ordering::Relaxed)
+        );
+        println!(
+            "Oversize datagrams: {}",
+            self.oversize_datagrams.load(Ordering::Relaxed)
+        );
+        println!(
+            "Outbound queued: {}",
+            self.outbound_queued.load(Ordering::Relaxed)
+        );
+        println!(
+            "Outbound dropped: {}",
+            self.outbound_dropped.load(Ordering::Relaxed)
+        );
+        println!(
+            "Slow consumer disconnects: {}",
+            self.slow_consumer_disconnects.load(Ordering::Relaxed)
+        );
+        println!(
+            "Active datagram tasks: {}",
+            self.datagram_tasks_active.load(Ordering::Relaxed)
+        );
+        println!(
+            "Datagram throttles: {}",
+            self.datagram_throttles.load(Ordering::Relaxed)
+        );
+
+        print_histogram("Request latency", &self.request_latency, |v| {
+            format!("{:?}", Duration::from_secs_f64(v))
+        });
+        print_histogram("Message size", &self.message_size, |v| {
+            format!("{} bytes", v.round())
+        });
+        print_histogram("Connection duration", &self.connection_duration, |v| {
+            format!("{:?}", Duration::from_secs_f64(v))
+        });
+
+        for counter in self.labeled.snapshot() {
+            println!(
+                "{}{{{}={}}}: {}",
+                counter.name, counter.label, counter.value, counter.count
+            );
+        }
+        for (index, connections, datagrams) in self.shards.snapshot() {
+            println!(
+                "Shard {}: {} connections, {} datagrams",
+                index, connections, datagrams
+            );
+        }
         println!("======================");
     }
 }
 
-pub async fn run_tcp<F, Fut>(addr: &str, handler: F) -> Result<(), Box<dyn Error>>
-where
-    F: Fn(TcpStream, SocketAddr, Metrics) -> Fut + Send + Sync + 'static + Copy,
-    Fut: std::future::Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
-{
-    let listener = TcpListener::bind(addr).await?;
-    let metrics = Metrics::new();
+pub async fn run_tcp<H: TcpHandler>(addr: &str, handler: H) -> Result<(), Box<dyn Error>> {
+    run_tcp_with_shutdown(addr, handler, Shutdown::on_signals()).await
+}
+
+pub async fn run_tcp_with_shutdown<H: TcpHandler>(
+    addr: &str,
+    handler: H,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let server = ServerBuilder::new(addr)
+        .shutdown(shutdown)
+        .tcp(handler)
+        .await?;
+    server.wait().await?;
+    Ok(())
+}
 
-    log_info!(addr, "Server started");
+pub async fn run_tcp_with_config<H: TcpHandler>(
+    config: &ServerConfig,
+    handler: H,
+) -> Result<(), Box<dyn Error>> {
+    config.apply_logging();
+    let server = ServerBuilder::from_config(config)
+        .shutdown(Shutdown::on_signals())
+        .tcp(handler)
+        .await?;
+    server.wait().await?;
+    Ok(())
+}
 
-    let metrics_clone = metrics.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
-        loop {
-            interval.tick().await;
-            metrics_clone.print_stats();
-        }
+pub async fn run_udp<H: UdpHandler>(addr: &str, handler: H) -> Result<(), Box<dyn Error>> {
+    run_udp_with_shutdown(addr, handler, Shutdown::on_signals()).await
+}
+
+pub async fn run_udp_with_shutdown<H: UdpHandler>(
+    addr: &str,
+    handler: H,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn Error>> {
+    let server = ServerBuilder::new(addr)
+        .shutdown(shutdown)
+        .udp(handler)
+        .await?;
+    server.wait().await?;
+    Ok(())
+}
+
+pub async fn run_udp_with_config<H: UdpHandler>(
+    config: &ServerConfig,
+    handler: H,
+) -> Result<(), Box<dyn Error>> {
+    config.apply_logging();
+    let server = ServerBuilder::from_config(config)
+        .shutdown(Shutdown::on_signals())
+        .udp(handler)
+        .await?;
+    server.wait().await?;
+    Ok(())
+}
+
+pub(crate) async fn serve_tcp<H: TcpHandler>(
+    listeners: Vec<Listener>,
+    handler: H,
+    metrics: Metrics,
+    shutdown: Shutdown,
+    options: ServeOptions,
+) -> io::Result<()> {
+    let addrs = listeners
+        .iter()
+        .map(Listener::local_addr)
+        .collect::<io::Result<Vec<_>>>()?;
+    for addr in &addrs {
+        log_info!(addr, "Server started");
+    }
+    let name = addrs
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let stats = spawn_stats(metrics.clone(), options.stats_interval);
+
+    if options.faults.is_tcp_enabled() {
+        log_warning!(name, format!("Injecting faults: {:?}", options.faults));
+    }
+    let server = Arc::new(TcpServer {
+        handler,
+        metrics: metrics.clone(),
+        admission: Admission::new(options.admission),
+        connections: options.connections,
+        tls: options.tls,
+        faults: options.faults,
+        capture: match &options.capture_dir {
+            Some(dir) => Some(CaptureDir::create(dir, &addrs[0])?),
+            None => None,
+        },
+        timeouts: options.timeouts,
+        proxy_protocol: options.proxy_protocol,
+        grace_period: shutdown.grace_period(),
+        closing: CancellationToken::new(),
     });
 
+    let shard = options.shard.map(|index| metrics.shards.shard(index));
+    let mut tasks = JoinSet::new();
+    let mut next_listener = 0;
     loop {
-        let (stream, client_addr) = listener.accept().await?;
-        log_info!(client_addr, "New connection");
+        tokio::select! {
+            (index, accepted) = accept_any(&listeners, next_listener) => {
+                next_listener = index + 1;
+                let (stream, client_addr) = match accepted {
+                    Ok(accepted) => accepted,
+                    // Live connections keep being served while this passes
+                    Err(e) => {
+                        metrics.error_in(ErrorCategory::Io);
+                        log_error!(addrs[index], format!("Accept error: {}", e));
+                        tokio::select! {
+                            _ = sleep(ACCEPT_BACKOFF) => continue,
+                            _ = shutdown.wait() => break,
+                        }
+                    }
+                };
+                if let Some(shard) = &shard {
+                    shard.connections.fetch_add(1, Ordering::Relaxed);
+                }
+                let local = addrs[index].clone();
+                tasks.spawn(server.clone().connection(stream, client_addr, local));
+            }
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    drop(listeners);
+    server.closing.cancel();
+    finish(
+        name,
+        &mut tasks,
+        &shutdown,
+        &metrics,
+        stats,
+        options.final_stats,
+    )
+    .await;
+    server.handler.shutdown().await;
+
+    Ok(())
+}
+
+/// What the connections of one TCP server share.
+struct TcpServer<H> {
+    handler: H,
+    metrics: Metrics,
+    admission: Arc<Admission>,
+    connections: Connections,
+    tls: Option<TlsAcceptor>,
+    faults: Faults,
+    capture: Option<CaptureDir>,
+    timeouts: Timeouts,
+    /// Sources that must open with a PROXY protocol header.
+    proxy_protocol: Vec<IpNet>,
+    grace_period: Duration,
+    /// Cancels every connection's context once the server starts shutting down.
+    closing: CancellationToken,
+}
+
+impl<H: TcpHandler> TcpServer<H> {
+    async fn connection(
+        self: Arc<Self>,
+        mut stream: Incoming,
+        mut client_addr: Address,
+        local: Address,
+    ) {
+        let proxied = client_addr
+            .ip()
+            .is_some_and(|ip| self.proxy_protocol.iter().any(|net| net.contains(ip)));
+        let proxied = proxied && matches!(stream, Incoming::Tcp(_));
+
+        // A balancer's connections only count towards the per-IP limits once the header
+        // says whose they are, but take a slot while it is read
+        let admitted = self
+            .admission
+            .try_admit(client_addr.ip().filter(|_| !proxied));
+        let mut permit = match admitted {
+            Ok(permit) => permit,
+            Err(rejection) => {
+                self.metrics.connection_rejected();
+                log_warning!(client_addr, format!("Connection rejected: {}", rejection));
+                return;
+            }
+        };
+
+        if proxied && let Incoming::Tcp(tcp) = &mut stream {
+            let limit = self
+                .timeouts
+                .handshake
+                .or(self.timeouts.idle)
+                .unwrap_or(PROXY_HEADER_TIMEOUT);
+            let header = tokio::time::timeout(limit, proxy_protocol::read_header(tcp))
+                .await
+                .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "timed out")));
+            match header {
+                Ok(Some(addr)) => client_addr = Address::Inet(addr),
+                Ok(None) => {}
+                Err(e) => {
+                    self.metrics.proxy_header_failed();
+                    log_warning!(client_addr, format!("Bad PROXY protocol header: {}", e));
+                    return;
+                }
+            }
+            if let Err(rejection) = permit.rekey(client_addr.ip()) {
+                self.metrics.connection_rejected();
+                log_warning!(client_addr, format!("Connection rejected: {}", rejection));
+                return;
+            }
+        }
+        let registration =
+            self.connections
+                .register(client_addr.clone(), local.clone(), &self.closing);
+        let id = registration.id;
+        let context = ConnectionContext::new(
+            id,
+            client_addr.clone(),
+            local,
+            registration.traffic.clone(),
+            self.metrics.clone(),
+            registration.cancellation.clone(),
+            registration.kick.clone(),
+        );
+
+        self.metrics.connection_opened();
+        log::with_connection(id, async move {
+            log_info!(client_addr, "New connection");
+            let metrics = &self.metrics;
+            let accepted = Stream::accept(
+                stream,
+                self.tls.as_ref(),
+                &self.faults,
+                metrics.clone(),
+                self.timeouts,
+            )
+            .await;
 
-        metrics.connection_opened();
-        let metrics_clone = metrics.clone();
+            let result = match accepted {
+                Ok(mut stream) => {
+                    if let Some(capture) = &self.capture {
+                        match stream
+                            .local_addr()
+                            .and_then(|local| capture.tcp(id, &local, client_addr.clone()))
+                        {
+                            Ok(recorder) => stream.capture_to(recorder),
+                            Err(e) => {
+                                log_warning!(client_addr, format!("Traffic capture failed: {}", e))
+                            }
+                        }
+                    }
+                    stream.track(context.clone(), registration.kick.clone());
+                    let held = stream.held_writes();
+                    let session = self.handler.handle(stream, context.clone());
+
+                    // Backstops for handlers that don't wind down when their stream
+                    // starts failing
+                    let expired = async {
+                        match self.timeouts.lifetime {
+                            Some(lifetime) => sleep(lifetime + self.grace_period).await,
+                            None => pending().await,
+                        }
+                    };
+                    let kicked = async {
+                        registration.kick.cancelled().await;
+                        sleep(self.grace_period).await
+                    };
+                    let result = tokio::select! {
+                        result = session => result,
+                        _ = expired => {
+                            log_warning!(client_addr, "Handler outlived session lifetime, dropping");
+                            Ok(())
+                        }
+                        _ = kicked => {
+                            log_warning!(client_addr, "Handler ignored kick, dropping");
+                            Ok(())
+                        }
+                    };
 
-        tokio::spawn(async move {
-            let result = handler(stream, client_addr, metrics_clone.clone()).await;
-            metrics_clone.connection_closed();
+                    // Handlers may return without flushing what they wrote last
+                    if let Some(held) = held {
+                        let _ = tokio::time::timeout(self.grace_period, held.send()).await;
+                    }
+                    result
+                }
+                // Only the TLS handshake can fail here
+                Err(e) => {
+                    metrics.tls_handshake_failed();
+                    log_warning!(client_addr, format!("TLS handshake failed: {}", e));
+                    Ok(())
+                }
+            };
+            metrics.connection_closed();
+            metrics.connection_duration.observe_duration(context.age());
+            drop(permit);
+            drop(registration);
 
             if let Err(e) = result {
-                metrics_clone.error_occurred();
-                log_error!(client_addr, format!("Connection error: {}", e));
+                metrics.error_in(e.category());
+                log::log_at(
+                    e.category().level(),
+                    &client_addr,
+                    &format!("Connection error: {}", e),
+                );
             }
-        });
+        })
+        .await
     }
 }
 
-pub async fn run_udp<F, Fut>(addr: &str, handler: F) -> Result<(), Box<dyn Error>>
-where
-    F: Fn(Vec<u8>, SocketAddr, Arc<UdpSocket>, Metrics) -> Fut + Send + Sync + 'static + Copy,
-    Fut: std::future::Future<Output = Result<(), Box<dyn Error>>> + Send + 'static,
-{
-    let socket = UdpSocket::bind(addr).await?;
+pub(crate) async fn serve_udp<H: UdpHandler>(
+    socket: UdpSocket,
+    handler: H,
+    metrics: Metrics,
+    shutdown: Shutdown,
+    options: ServeOptions,
+) -> io::Result<()> {
+    let addr = socket.local_addr()?;
     let socket = Arc::new(socket);
-    let metrics = Metrics::new();
+    let capture = match &options.capture_dir {
+        Some(dir) => Some(CaptureDir::create(dir, &addr.into())?),
+        None => None,
+    };
+    let replies = DatagramSocket::new(socket.clone(), capture);
 
     log_info!(addr, "Server started");
 
-    let metrics_clone = metrics.clone();
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(Duration::from_secs(30));
-        loop {
-            interval.tick().await;
-            metrics_clone.print_stats();
-        }
-    });
+    let stats = spawn_stats(metrics.clone(), options.stats_interval);
 
-    let mut buf = [0; 1024];
+    let handler = Arc::new(handler);
+    let limits = options.datagram_limits;
+    let mut receiver = Receiver::new(limits.max_size);
+    let faults = options.faults;
+    if faults.is_enabled() {
+        log_warning!(addr, format!("Injecting faults: {:?}", faults));
+    }
+    let mut dice = Dice::new();
+    let in_flight = Arc::new(Semaphore::new(limits.max_in_flight));
+    let shard = options.shard.map(|index| metrics.shards.shard(index));
+    let local = Address::from(addr);
+    let closing = CancellationToken::new();
+    let mut tasks = JoinSet::new();
+    let mut next_id = 0u64;
     loop {
-        match socket.recv_from(&mut buf).await {
-            Ok((len, client_addr)) => {
-                metrics.bytes_received(len as u64);
-                log_msg_in!(client_addr, format!("UDP packet ({} bytes)", len));
-
-                let socket_clone = socket.clone();
-                let metrics_clone = metrics.clone();
-                let packet_data = buf[..len].to_vec();
-
-                tokio::spawn(async move {
-                    if let Err(e) = handler(
-                        packet_data,
-                        client_addr,
-                        socket_clone,
-                        metrics_clone.clone(),
-                    )
-                    .await
-                    {
-                        metrics_clone.error_occurred();
-                        log_error!(client_addr, format!("Handler error: {}", e));
+        tokio::select! {
+            received = receiver.recv_from(&socket) => match received {
+                Ok((packet_data, len, client_addr)) => {
+                    next_id += 1;
+                    if let Some(shard) = &shard {
+                        shard.datagrams.fetch_add(1, Ordering::Relaxed);
                     }
-                });
-            }
-            Err(e) => {
-                metrics.error_occurred();
-                log_error!(addr, format!("UDP recv error: {}", e));
-            }
+                    let context = ConnectionContext::new(
+                        next_id,
+                        client_addr.into(),
+                        local.clone(),
+                        Default::default(),
+                        metrics.clone(),
+                        closing.child_token(),
+                        // Datagrams can't be kicked
+                        CancellationToken::new(),
+                    );
+                    context.bytes_received(len as u64);
+                    context.message_received(len);
+                    replies.record(client_addr, Direction::Received, &packet_data);
+
+                    if packet_data.is_truncated() {
+                        metrics.datagram_oversize();
+                        if limits.oversize == OversizePolicy::Drop {
+                            log_warning!(
+                                client_addr,
+                                format!("Dropped datagram over {} bytes", limits.max_size)
+                            );
+                            continue;
+                        }
+                    }
+
+                    let (copies, delay) = match faults.datagram_fate(&mut dice, &metrics) {
+                        Fate::Drop => {
+                            log_msg_in!(client_addr, "Dropped datagram (injected fault)");
+                            continue;
+                        }
+                        Fate::Deliver { copies, delay } => (copies, delay),
+                    };
+
+                    for _ in 0..copies {
+                        let permit = match in_flight.clone().try_acquire_owned() {
+                            Ok(permit) => permit,
+                            Err(_) => {
+                                metrics.datagram_throttled();
+                                tokio::select! {
+                                    permit = in_flight.clone().acquire_owned() => {
+                                        permit.expect("semaphore is never closed")
+                                    }
+                                    // Leaves the copies undelivered, the loop then stops
+                                    _ = shutdown.wait() => break,
+                                }
+                            }
+                        };
+                        let packet_data = packet_data.clone();
+                        let socket = replies.for_context(context.clone());
+                        let context = context.clone();
+                        let handler = handler.clone();
+
+                        let active = DatagramTask::start(&metrics);
+                        tasks.spawn(log::with_connection(next_id, async move {
+                            let _slot = (permit, active);
+                            if !delay.is_zero() {
+                                sleep(delay).await;
+                            }
+                            log_msg_in!(client_addr, format!("UDP packet ({} bytes)", len));
+                            let started = Instant::now();
+                            let metrics = context.metrics().clone();
+                            let result = handler.handle(packet_data, socket, context).await;
+                            metrics.request_completed(started.elapsed());
+
+                            if let Err(e) = result {
+                                metrics.error_in(e.category());
+                                log::log_at(
+                                    e.category().level(),
+                                    &client_addr,
+                                    &format!("Handler error: {}", e),
+                                );
+                            }
+                        }));
+                    }
+                }
+                Err(e) => {
+                    metrics.error_in(ErrorCategory::Io);
+                    log_error!(addr, format!("UDP recv error: {}", e));
+                }
+            },
+            Some(_) = tasks.join_next(), if !tasks.is_empty() => {}
+            _ = shutdown.wait() => break,
+        }
+    }
+
+    closing.cancel();
+
+    finish(
+        addr,
+        &mut tasks,
+        &shutdown,
+        &metrics,
+        stats,
+        options.final_stats,
+    )
+    .await;
+    handler.shutdown().await;
+
+    Ok(())
+}
+
+fn print_histogram(name: &str, histogram: &Histogram, format: impl Fn(f64) -> String) {
+    let snapshot = histogram.snapshot();
+    let (Some(mean), Some(p50), Some(p99)) = (
+        snapshot.mean(),
+        snapshot.quantile(0.5),
+        snapshot.quantile(0.99),
+    ) else {
+        return;
+    };
+
+    let bound = |v: f64| {
+        if v.is_finite() {
+            format!("<= {}", format(v))
+        } else {
+            "over largest bucket".to_string()
+        }
+    };
+    println!(
+        "{}: {} observed, mean {}, p50 {}, p99 {}",
+        name,
+        snapshot.count,
+        format(mean),
+        bound(p50),
+        bound(p99)
+    );
+}
+
+/// Counts a UDP handler task in `datagram_tasks_active` until dropped.
+struct DatagramTask(Metrics);
+
+impl DatagramTask {
+    fn start(metrics: &Metrics) -> Self {
+        metrics
+            .datagram_tasks_active
+            .fetch_add(1, Ordering::Relaxed);
+        Self(metrics.clone())
+    }
+}
+
+impl Drop for DatagramTask {
+    fn drop(&mut self) {
+        self.0.datagram_tasks_active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Prints `metrics` every `stats_interval` from a new task, unless it is `None` or zero.
+pub fn spawn_stats(metrics: Metrics, stats_interval: Option<Duration>) -> Option<JoinHandle<()>> {
+    let stats_interval = stats_interval.filter(|interval| !interval.is_zero())?;
+    Some(tokio::spawn(async move {
+        let mut interval = tokio::time::interval(stats_interval);
+        loop {
+            interval.tick().await;
+            metrics.print_stats();
         }
+    }))
+}
+
+async fn finish(
+    addr: impl fmt::Display,
+    tasks: &mut JoinSet<()>,
+    shutdown: &Shutdown,
+    metrics: &Metrics,
+    stats: Option<JoinHandle<()>>,
+    final_stats: bool,
+) {
+    log_info!(
+        addr,
+        format!("Shutting down, draining {} handler(s)", tasks.len())
+    );
+
+    let aborted = shutdown::drain(tasks, shutdown.grace_period()).await;
+    if aborted > 0 {
+        log_warning!(
+            addr,
+            format!("Aborted {} handler(s) after grace period", aborted)
+        );
+    }
+
+    if let Some(stats) = stats {
+        stats.abort();
+    }
+    // Servers sharing their metrics leave the report to whoever owns them
+    if final_stats {
+        metrics.print_stats();
     }
 }
 
 #[macro_export]
 macro_rules! log_info {
     ($addr:expr, $msg:expr) => {
-        println!("[INFO] [{}] {}", $addr, $msg);
-    };
-    ($msg:expr, $addr:expr) => {
-        println!("[INFO] [SERVER] {}", format!($msg, $addr));
+        if $crate::log::enabled($crate::log::Level::Info) {
+            $crate::log::write($crate::log::Level::Info, "INFO", &$addr, &$msg);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! log_error {
     ($addr:expr, $msg:expr, $err:expr) => {
-        eprintln!("[ERROR] [{}] {}: {}", $addr, $msg, $err);
+        if $crate::log::enabled($crate::log::Level::Error) {
+            $crate::log::write(
+                $crate::log::Level::Error,
+                "ERROR",
+                &$addr,
+                &format_args!("{}: {}", $msg, $err),
+            );
+        }
     };
     ($addr:expr, $msg:expr) => {
-        eprintln!("[ERROR] [{}] {}", $addr, $msg);
+        if $crate::log::enabled($crate::log::Level::Error) {
+            $crate::log::write($crate::log::Level::Error, "ERROR", &$addr, &$msg);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! log_warning {
     ($addr:expr, $msg:expr) => {
-        println!("[WARNING] [{}] {}", $addr, $msg);
+        if $crate::log::enabled($crate::log::Level::Warning) {
+            $crate::log::write($crate::log::Level::Warning, "WARNING", &$addr, &$msg);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! log_msg_out {
     ($addr:expr, $msg:expr) => {
-        println!("[--->] [{}] {}", $addr, $msg);
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            $crate::log::write($crate::log::Level::Debug, "--->", &$addr, &$msg);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! log_msg_in {
     ($addr:expr, $msg:expr) => {
-        println!("[<---] [{}] {}", $addr, $msg);
+        if $crate::log::enabled($crate::log::Level::Debug) {
+            $crate::log::write($crate::log::Level::Debug, "<---", &$addr, &$msg);
+        }
     };
 }
@@ -0,0 +1,190 @@
+//! The sockets servers listen on.
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::{
+    fs, io,
+    net::SocketAddr,
+    os::{
+        fd::OwnedFd,
+        unix::net::{UnixListener as StdUnixListener, UnixStream as StdUnixStream},
+    },
+    path::{Path, PathBuf},
+    task::{Context, Poll},
+};
+use tokio::net::{TcpListener, TcpStream, UdpSocket, UnixListener, UnixStream, lookup_host};
+
+use crate::{
+    Address,
+    activation::{self, FD_PREFIX, Offer},
+};
+
+/// Listen addresses starting with this are Unix domain socket paths.
+pub const UNIX_PREFIX: &str = "unix:";
+
+const BACKLOG: i32 = 1024;
+
+pub(crate) struct Listener {
+    kind: Kind,
+    _offer: Offer,
+}
+
+enum Kind {
+    Tcp(TcpListener),
+    /// Removes its socket file, if it has one of its own, when dropped.
+    Unix(UnixListener, Option<PathBuf>),
+}
+
+/// A connection accepted from a [`Listener`].
+pub(crate) enum Incoming {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Splits a comma-separated list of listen addresses.
+pub fn split_addrs(addrs: &str) -> impl Iterator<Item = &str> {
+    addrs
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+}
+
+impl Listener {
+    /// Binds `addr`, either `host:port` or `unix:<path>`, unless a socket for it was
+    /// passed in. `fd:` addresses only name passed-in sockets. `reuse_port` lets other
+    /// sockets bind the same TCP address to share its connections.
+    pub(crate) async fn bind(addr: &str, reuse_port: bool) -> io::Result<Self> {
+        let kind = if let Some(fd) = activation::take(addr)? {
+            // Only a socket file handed down by our own previous process is ours
+            from_fd(fd, !addr.starts_with(FD_PREFIX))?
+        } else if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            bind_unix(Path::new(path))?
+        } else {
+            Kind::Tcp(bind_tcp(resolve(addr).await?, reuse_port)?)
+        };
+
+        let _offer = match &kind {
+            Kind::Tcp(listener) => activation::offer(addr, listener)?,
+            Kind::Unix(listener, _) => activation::offer(addr, listener)?,
+        };
+        Ok(Self { kind, _offer })
+    }
+
+    pub(crate) fn local_addr(&self) -> io::Result<Address> {
+        match &self.kind {
+            Kind::Tcp(listener) => listener.local_addr().map(Address::Inet),
+            Kind::Unix(listener, _) => listener.local_addr().map(Address::from_unix),
+        }
+    }
+
+    pub(crate) fn poll_accept(
+        &self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<(Incoming, Address)>> {
+        match &self.kind {
+            Kind::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (Incoming::Tcp(stream), Address::Inet(addr))),
+            Kind::Unix(listener, _) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, addr)| (Incoming::Unix(stream), Address::from_unix(addr))),
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // After a handover the replacement is listening on the same file
+        if let Kind::Unix(_, Some(path)) = &self.kind
+            && !activation::is_replaced()
+        {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+/// Waits for a connection on any of `listeners`, returning the index of the one it
+/// arrived on. Listeners are polled in turn from `start` so none can starve the rest.
+pub(crate) async fn accept_any(
+    listeners: &[Listener],
+    start: usize,
+) -> (usize, io::Result<(Incoming, Address)>) {
+    std::future::poll_fn(|cx| {
+        for offset in 0..listeners.len() {
+            let index = (start + offset) % listeners.len();
+            if let Poll::Ready(result) = listeners[index].poll_accept(cx) {
+                return Poll::Ready((index, result));
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Binds a UDP socket to `addr`; `reuse_port` lets others bind it too, each receiving
+/// the datagrams of its own share of clients.
+pub(crate) async fn bind_udp(addr: &str, reuse_port: bool) -> io::Result<UdpSocket> {
+    if !reuse_port {
+        return UdpSocket::bind(addr).await;
+    }
+    let addr = resolve(addr).await?;
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+async fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} did not resolve", addr)))
+}
+
+fn bind_tcp(addr: SocketAddr, reuse_port: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Otherwise an IPv6 wildcard also claims the port on IPv4, clashing with an
+    // explicit IPv4 listener
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(reuse_port)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    TcpListener::from_std(socket.into())
+}
+
+fn from_fd(fd: OwnedFd, owns_path: bool) -> io::Result<Kind> {
+    activation::check_type(&fd, Type::STREAM)?;
+    if !SockRef::from(&fd).is_listener()? {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "inherited socket isn't listening",
+        ));
+    }
+    if SockRef::from(&fd).domain()? == Domain::UNIX {
+        let listener = UnixListener::from_std(StdUnixListener::from(fd))?;
+        let path = match listener.local_addr()?.as_pathname() {
+            Some(path) if owns_path => Some(path.to_path_buf()),
+            _ => None,
+        };
+        Ok(Kind::Unix(listener, path))
+    } else {
+        Ok(Kind::Tcp(TcpListener::from_std(fd.into())?))
+    }
+}
+
+fn bind_unix(path: &Path) -> io::Result<Kind> {
+    let listener = match UnixListener::bind(path) {
+        // A socket file nobody answers on was left behind by a server that didn't exit
+        // cleanly
+        Err(e) if e.kind() == io::ErrorKind::AddrInUse && StdUnixStream::connect(path).is_err() => {
+            fs::remove_file(path)?;
+            UnixListener::bind(path)?
+        }
+        result => result?,
+    };
+    Ok(Kind::Unix(listener, Some(path.to_path_buf())))
+}
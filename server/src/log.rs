@@ -0,0 +1,201 @@
+use std::{
+    fmt::{self, Display, Write as _},
+    future::Future,
+    io::{self, Write},
+    str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+
+tokio::task_local! {
+    static CONNECTION_ID: Option<u64>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(u8)]
+pub enum Level {
+    Error = 1,
+    Warning = 2,
+    Info = 3,
+    /// Per-message traffic logged by `log_msg_in!` and `log_msg_out!`.
+    Debug = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LogFormat {
+    Text = 0,
+    /// One JSON object per line.
+    Json = 1,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Error => "error",
+            Self::Warning => "warning",
+            Self::Info => "info",
+            Self::Debug => "debug",
+        }
+    }
+
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Self::Error,
+            2 => Self::Warning,
+            3 => Self::Info,
+            _ => Self::Debug,
+        }
+    }
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "error" => Ok(Self::Error),
+            "warn" | "warning" => Ok(Self::Warning),
+            "info" => Ok(Self::Info),
+            "debug" => Ok(Self::Debug),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed))
+}
+
+pub fn set_format(format: LogFormat) {
+    FORMAT.store(format as u8, Ordering::Relaxed);
+}
+
+pub fn format() -> LogFormat {
+    match FORMAT.load(Ordering::Relaxed) {
+        1 => LogFormat::Json,
+        _ => LogFormat::Text,
+    }
+}
+
+#[inline]
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Returns the ID of the connection being handled by the current task, if any.
+pub fn connection_id() -> Option<u64> {
+    CONNECTION_ID.try_with(|id| *id).ok().flatten()
+}
+
+/// Runs `fut` with `id` attached to every log line it emits.
+pub fn with_connection<F: Future>(id: u64, fut: F) -> impl Future<Output = F::Output> {
+    CONNECTION_ID.scope(Some(id), fut)
+}
+
+/// Runs `fut` with the current task's connection ID, for tasks spawned by handlers.
+pub fn in_current_connection<F: Future>(fut: F) -> impl Future<Output = F::Output> {
+    CONNECTION_ID.scope(connection_id(), fut)
+}
+
+/// Logs `msg` at a level chosen at runtime, tagged like the matching `log_*!` macro.
+pub fn log_at(level: Level, addr: &dyn Display, msg: &dyn Display) {
+    if !enabled(level) {
+        return;
+    }
+
+    let tag = match level {
+        Level::Error => "ERROR",
+        Level::Warning => "WARNING",
+        Level::Info => "INFO",
+        Level::Debug => "DEBUG",
+    };
+    write(level, tag, addr, msg);
+}
+
+#[doc(hidden)]
+pub fn write(level: Level, tag: &str, addr: &dyn Display, msg: &dyn Display) {
+    let line = match format() {
+        LogFormat::Text => text_line(tag, addr, msg),
+        LogFormat::Json => json_line(level, tag, addr, msg),
+    };
+
+    // Logging must never take the server down, so write errors are ignored
+    let _ = if level == Level::Error {
+        io::stderr().lock().write_all(line.as_bytes())
+    } else {
+        io::stdout().lock().write_all(line.as_bytes())
+    };
+}
+
+fn text_line(tag: &str, addr: &dyn Display, msg: &dyn Display) -> String {
+    match connection_id() {
+        Some(id) => format!("[{}] [{}] [#{}] {}\n", tag, addr, id, msg),
+        None => format!("[{}] [{}] {}\n", tag, addr, msg),
+    }
+}
+
+fn json_line(level: Level, tag: &str, addr: &dyn Display, msg: &dyn Display) -> String {
+    let ts = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    let mut line = format!("{{\"ts\":{:.6},\"level\":\"{}\",\"addr\":", ts, level);
+    push_json_string(&mut line, &addr.to_string());
+    if let Some(id) = connection_id() {
+        let _ = write!(line, ",\"conn\":{}", id);
+    }
+    match tag {
+        "<---" => line.push_str(",\"dir\":\"in\""),
+        "--->" => line.push_str(",\"dir\":\"out\""),
+        _ => {}
+    }
+    line.push_str(",\"msg\":");
+    push_json_string(&mut line, &msg.to_string());
+    line.push_str("}\n");
+    line
+}
+
+fn push_json_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,306 @@
+//! HAProxy PROXY protocol headers, which load balancers send ahead of a connection's
+//! data to pass on the client's address. Both the v1 text and v2 binary forms are read;
+//! see <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+// Longest v1 line allowed, CRLF included
+const V1_MAX_LENGTH: usize = 107;
+
+/// An IP network such as `10.0.0.0/8`. A bare address is a network of one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix_len: u32,
+}
+
+impl IpNet {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_len).unwrap_or(0);
+                (u32::from(net) ^ u32::from(ip)) & mask == 0
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_len).unwrap_or(0);
+                (u128::from(net) ^ u128::from(ip)) & mask == 0
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|_| s.to_string())?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .parse()
+                .ok()
+                .filter(|&len| len <= max)
+                .ok_or_else(|| s.to_string())?,
+            None => max,
+        };
+        Ok(Self { addr, prefix_len })
+    }
+}
+
+/// Reads the header a load balancer sends first, returning the client address it
+/// carries. `None` means the connection's own address stands: the balancer connected
+/// on its own behalf (v1 `UNKNOWN`, v2 `LOCAL`) or relayed something other than TCP.
+///
+/// Only the header is consumed, reading v1 lines a byte at a time so none of the
+/// client's data is read ahead.
+pub(crate) async fn read_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<SocketAddr>> {
+    // Every v1 header is longer than the v2 signature. Clients talking the problem's
+    // own protocol are caught by their first byte rather than waiting for the rest.
+    let mut start = [0; V2_SIGNATURE.len()];
+    stream.read_exact(&mut start[..1]).await?;
+    if start[0] != V1_PREFIX[0] && start[0] != V2_SIGNATURE[0] {
+        return Err(invalid("missing PROXY protocol header"));
+    }
+    stream.read_exact(&mut start[1..]).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(V1_PREFIX) {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("missing PROXY protocol header"))
+    }
+}
+
+async fn read_v1(
+    stream: &mut (impl AsyncRead + Unpin),
+    start: &[u8],
+) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .map_err(|_| invalid("bad PROXY v1 source address"))?;
+            if ip.is_ipv4() != (family == "TCP4") {
+                return Err(invalid("PROXY v1 address doesn't match its family"));
+            }
+            let port = port
+                .parse()
+                .map_err(|_| invalid("bad PROXY v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("malformed PROXY v1 header")),
+    }
+}
+
+async fn read_v2(stream: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    let version_command = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+    let mut body = vec![0; len as usize];
+    stream.read_exact(&mut body).await?;
+
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. the balancer's own health checks
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unknown PROXY v2 command")),
+    }
+
+    // Addresses come first, any TLVs after them are skipped
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let Some(addrs) = body.get(..12) else {
+                return Err(invalid("PROXY v2 IPv4 addresses cut short"));
+            };
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addrs[..4]).unwrap());
+            let port = u16::from_be_bytes([addrs[8], addrs[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let Some(addrs) = body.get(..36) else {
+                return Err(invalid("PROXY v2 IPv6 addresses cut short"));
+            };
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addrs[..16]).unwrap());
+            let port = u16::from_be_bytes([addrs[32], addrs[33]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        _ => Ok(None),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(mut input: &[u8]) -> (io::Result<Option<SocketAddr>>, &[u8]) {
+        let header = read_header(&mut input).await;
+        (header, input)
+    }
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x20 | command, family]);
+        header.extend((body.len() as u16).to_be_bytes());
+        header.extend(body);
+        header
+    }
+
+    #[tokio::test]
+    async fn v1_tcp4() {
+        let (header, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nhello").await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v1_tcp6_and_unknown() {
+        let (header, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 443\r\n").await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+        let (header, rest) = read(b"PROXY UNKNOWN ffff::1 ffff::2 1 2\r\nx").await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"x");
+    }
+
+    #[tokio::test]
+    async fn v1_rejects_bad_headers() {
+        for input in [
+            &b"PROXY TCP4 2001:db8::1 192.0.2.2 1 2\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 99999 2\r\n",
+            b"PROXY TCP4 192.0.2.1\r\n",
+            b"PROXY NOPE\r\n",
+            b"PRAXY TCP4 192.0.2.1 192.0.2.2 1 2\r\n",
+            b"hello\n",
+        ] {
+            let (header, _) = read(input).await;
+            assert_eq!(header.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        let mut long = b"PROXY TCP4 ".to_vec();
+        long.resize(200, b'1');
+        assert!(read(&long).await.0.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_tcp4() {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        body.extend(56324u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+        // A TLV after the addresses is skipped
+        body.extend([0x04, 0, 1, 0]);
+        let mut input = v2(0x1, 0x11, &body);
+        input.extend(b"hello");
+
+        let (header, rest) = read(&input).await;
+        assert_eq!(header.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"hello");
+    }
+
+    #[tokio::test]
+    async fn v2_tcp6() {
+        let mut body = "2001:db8::1".parse::<Ipv6Addr>().unwrap().octets().to_vec();
+        body.extend("2001:db8::2".parse::<Ipv6Addr>().unwrap().octets());
+        body.extend(4000u16.to_be_bytes());
+        body.extend(443u16.to_be_bytes());
+
+        let (header, _) = read(&v2(0x1, 0x21, &body)).await;
+        assert_eq!(header.unwrap(), Some("[2001:db8::1]:4000".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn v2_local_and_other_families() {
+        let mut input = v2(0x0, 0x00, &[]);
+        input.push(b'x');
+        let (header, rest) = read(&input).await;
+        assert_eq!(header.unwrap(), None);
+        assert_eq!(rest, b"x");
+        // UDP over IPv4 keeps the connection's own address
+        let (header, _) = read(&v2(0x1, 0x12, &[0; 12])).await;
+        assert_eq!(header.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_rejects_bad_headers() {
+        let mut wrong_version = v2(0x1, 0x11, &[0; 12]);
+        wrong_version[12] = 0x11;
+        for input in [
+            wrong_version,
+            v2(0x2, 0x11, &[0; 12]),
+            v2(0x1, 0x11, &[0; 8]),
+            v2(0x1, 0x21, &[0; 12]),
+        ] {
+            let (header, _) = read(&input).await;
+            assert_eq!(header.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+
+        // Cut short before the declared length
+        let input = v2(0x1, 0x11, &[0; 12]);
+        let (header, _) = read(&input[..20]).await;
+        assert_eq!(header.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn ip_net_contains() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains("10.1.255.3".parse().unwrap()));
+        assert!(!net.contains("10.2.0.1".parse().unwrap()));
+        // IPv4-mapped IPv6 addresses count as IPv4
+        assert!(net.contains("::ffff:10.1.0.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let net: IpNet = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:ffff::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        let host: IpNet = "192.0.2.7".parse().unwrap();
+        assert!(host.contains("192.0.2.7".parse().unwrap()));
+        assert!(!host.contains("192.0.2.8".parse().unwrap()));
+
+        let any: IpNet = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("203.0.113.9".parse().unwrap()));
+        let any: IpNet = "::/0".parse().unwrap();
+        assert!(any.contains("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn ip_net_parse_errors() {
+        for input in ["10.0.0.0/33", "::/129", "10.0.0.0/x", "nope", "10.0.0/8"] {
+            assert!(input.parse::<IpNet>().is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,329 @@
+//! Bounded outbound queues, so a client that reads slowly can't make the server buffer
+//! messages for it without limit.
+//!
+//! Queued messages are counted in the `outbound_queue_depth` gauge, and overflows in
+//! `outbound_dropped_total` or `slow_consumer_disconnects_total`.
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::Notify;
+
+use crate::{HandlerError, Metrics};
+
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Size of a connection's outbound queue and what happens when it fills up.
+#[derive(Debug, Clone, Copy)]
+pub struct QueueLimits {
+    /// Most messages held for a client at once.
+    pub capacity: usize,
+    pub overflow: OverflowPolicy,
+}
+
+/// What happens to a message sent to a full queue.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Discard the oldest queued message to make room.
+    DropOldest,
+    /// Discard the new message.
+    DropNewest,
+    /// Discard everything queued and fail the receiver with [`QueueOverflow`].
+    #[default]
+    Disconnect,
+}
+
+/// The receiving end of a queue was overrun under [`OverflowPolicy::Disconnect`].
+#[derive(Debug)]
+pub struct QueueOverflow;
+
+/// Sends to a queue; clones share it.
+pub struct QueueSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+/// Receives from a queue, usually in the task writing to the client.
+pub struct QueueReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+struct Shared<T> {
+    state: Mutex<State<T>>,
+    ready: Notify,
+    limits: QueueLimits,
+    metrics: Metrics,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    senders: usize,
+    receiver_alive: bool,
+    overflowed: bool,
+}
+
+impl Default for QueueLimits {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_CAPACITY,
+            overflow: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().replace('-', "_").as_str() {
+            "drop_oldest" => Ok(Self::DropOldest),
+            "drop_newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err(s.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for QueueOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "outbound queue overflowed, client is reading too slowly")
+    }
+}
+
+impl Error for QueueOverflow {}
+
+impl From<QueueOverflow> for HandlerError {
+    fn from(e: QueueOverflow) -> Self {
+        Self::protocol(e.to_string())
+    }
+}
+
+/// Creates a queue bounded by `limits`.
+pub fn queue<T>(limits: QueueLimits, metrics: &Metrics) -> (QueueSender<T>, QueueReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            items: VecDeque::new(),
+            senders: 1,
+            receiver_alive: true,
+            overflowed: false,
+        }),
+        ready: Notify::new(),
+        limits,
+        metrics: metrics.clone(),
+    });
+    (
+        QueueSender {
+            shared: shared.clone(),
+        },
+        QueueReceiver { shared },
+    )
+}
+
+impl<T> QueueSender<T> {
+    /// Queues `item` without waiting, applying the overflow policy if the queue is
+    /// full. Returns whether `item` was queued.
+    pub fn send(&self, item: T) -> bool {
+        let shared = &*self.shared;
+        let metrics = &shared.metrics;
+        let mut state = shared.state.lock().unwrap();
+        if !state.receiver_alive || state.overflowed {
+            return false;
+        }
+
+        if state.items.len() >= shared.limits.capacity {
+            match shared.limits.overflow {
+                OverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    metrics.outbound_dropped();
+                }
+                OverflowPolicy::DropNewest => {
+                    metrics.outbound_dropped();
+                    return false;
+                }
+                OverflowPolicy::Disconnect => {
+                    let discarded = state.items.len() as u64;
+                    state.items.clear();
+                    state.overflowed = true;
+                    metrics.outbound_dequeued(discarded);
+                    metrics.slow_consumer_disconnected();
+                    shared.ready.notify_one();
+                    return false;
+                }
+            }
+        } else {
+            metrics.outbound_queued();
+        }
+        state.items.push_back(item);
+        shared.ready.notify_one();
+        true
+    }
+}
+
+impl<T> Clone for QueueSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Drop for QueueSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            self.shared.ready.notify_one();
+        }
+    }
+}
+
+impl<T> QueueReceiver<T> {
+    /// Waits for the next message. `None` means every sender is gone and the queue is
+    /// empty.
+    pub async fn recv(&mut self) -> Result<Option<T>, QueueOverflow> {
+        loop {
+            if let Some(received) = self.poll_once() {
+                return received;
+            }
+            self.shared.ready.notified().await;
+        }
+    }
+
+    /// Takes the next message if one is waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.poll_once().and_then(Result::ok).flatten()
+    }
+
+    /// Messages currently queued.
+    pub fn len(&self) -> usize {
+        self.shared.state.lock().unwrap().items.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn poll_once(&mut self) -> Option<Result<Option<T>, QueueOverflow>> {
+        let mut state = self.shared.state.lock().unwrap();
+        if state.overflowed {
+            return Some(Err(QueueOverflow));
+        }
+        if let Some(item) = state.items.pop_front() {
+            self.shared.metrics.outbound_dequeued(1);
+            return Some(Ok(Some(item)));
+        }
+        (state.senders == 0).then_some(Ok(None))
+    }
+}
+
+impl<T> Drop for QueueReceiver<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.receiver_alive = false;
+        let discarded = state.items.len() as u64;
+        state.items.clear();
+        self.shared.metrics.outbound_dequeued(discarded);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use super::*;
+
+    fn bounded(
+        overflow: OverflowPolicy,
+        metrics: &Metrics,
+    ) -> (QueueSender<u32>, QueueReceiver<u32>) {
+        queue(
+            QueueLimits {
+                capacity: 2,
+                overflow,
+            },
+            metrics,
+        )
+    }
+
+    fn load(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    #[tokio::test]
+    async fn drop_oldest() {
+        let metrics = Metrics::new();
+        let (sender, mut receiver) = bounded(OverflowPolicy::DropOldest, &metrics);
+        assert!(sender.send(1) && sender.send(2) && sender.send(3));
+        assert_eq!(load(&metrics.outbound_dropped), 1);
+        assert_eq!(load(&metrics.outbound_queued), 2);
+
+        drop(sender);
+        assert_eq!(receiver.recv().await.unwrap(), Some(2));
+        assert_eq!(receiver.recv().await.unwrap(), Some(3));
+        assert_eq!(receiver.recv().await.unwrap(), None);
+        assert_eq!(load(&metrics.outbound_queued), 0);
+    }
+
+    #[tokio::test]
+    async fn drop_newest() {
+        let metrics = Metrics::new();
+        let (sender, mut receiver) = bounded(OverflowPolicy::DropNewest, &metrics);
+        assert!(sender.send(1) && sender.send(2));
+        assert!(!sender.send(3));
+        assert_eq!(load(&metrics.outbound_dropped), 1);
+
+        assert_eq!(receiver.recv().await.unwrap(), Some(1));
+        assert!(sender.send(4));
+        drop(sender);
+        assert_eq!(receiver.recv().await.unwrap(), Some(2));
+        assert_eq!(receiver.recv().await.unwrap(), Some(4));
+        assert_eq!(receiver.recv().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn disconnect() {
+        let metrics = Metrics::new();
+        let (sender, mut receiver) = bounded(OverflowPolicy::Disconnect, &metrics);
+        assert!(sender.send(1) && sender.send(2));
+        assert!(!sender.send(3));
+        assert_eq!(load(&metrics.slow_consumer_disconnects), 1);
+        assert_eq!(load(&metrics.outbound_queued), 0);
+
+        // Queued messages are discarded and later sends refused
+        assert!(receiver.recv().await.is_err());
+        assert!(!sender.send(4));
+        assert!(receiver.is_empty());
+    }
+
+    #[tokio::test]
+    async fn recv_waits_for_a_send() {
+        let metrics = Metrics::new();
+        let (sender, mut receiver) = bounded(OverflowPolicy::Disconnect, &metrics);
+        let waiting = tokio::spawn(async move { receiver.recv().await.unwrap() });
+        tokio::task::yield_now().await;
+        sender.clone().send(7);
+        assert_eq!(waiting.await.unwrap(), Some(7));
+    }
+
+    #[test]
+    fn sends_after_the_receiver_is_gone_fail() {
+        let metrics = Metrics::new();
+        let (sender, receiver) = bounded(OverflowPolicy::DropOldest, &metrics);
+        assert!(sender.send(1));
+        drop(receiver);
+        assert!(!sender.send(2));
+        assert_eq!(load(&metrics.outbound_queued), 0);
+    }
+
+    #[test]
+    fn policy_names() {
+        assert_eq!("drop-oldest".parse(), Ok(OverflowPolicy::DropOldest));
+        assert_eq!("DROP_NEWEST".parse(), Ok(OverflowPolicy::DropNewest));
+        assert_eq!("disconnect".parse(), Ok(OverflowPolicy::Disconnect));
+        assert!("block".parse::<OverflowPolicy>().is_err());
+    }
+}
@@ -0,0 +1,273 @@
+//! Building the tokio runtime a server runs on, and the per-core mode that gives every
+//! worker its own `SO_REUSEPORT` listener and single-threaded runtime.
+//!
+//! Per-core shards share one set of metrics, one connection registry and one shutdown
+//! handle, so the exporter, admin listener and stats report cover all of them. Only
+//! the first shard runs the exporter and admin listener and reports stats periodically;
+//! the final report is printed once every shard has drained.
+
+use std::{
+    error::Error,
+    future::Future,
+    io,
+    num::NonZeroUsize,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc,
+    },
+    thread,
+};
+use tokio::runtime::{Builder, Runtime};
+
+use crate::{
+    ConnectionContext, Connections, Datagram, DatagramSocket, HandlerError, Metrics, ServerBuilder,
+    ServerConfig, ServerHandle, Shutdown, Stream, TcpHandler, UdpHandler, log_warning,
+};
+
+/// Threads the server runs on.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RuntimeOptions {
+    /// Threads running tasks, or shards in per-core mode; one per core by default.
+    pub worker_threads: Option<usize>,
+    /// Most threads kept for blocking work, per runtime.
+    pub max_blocking_threads: Option<usize>,
+    /// Gives each worker its own `SO_REUSEPORT` listener on a single-threaded runtime
+    /// instead of sharing one accept loop on a multi-threaded runtime.
+    pub per_core: bool,
+}
+
+impl RuntimeOptions {
+    pub fn workers(&self) -> usize {
+        self.worker_threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, NonZeroUsize::get))
+    }
+
+    /// Builds the multi-threaded runtime used outside per-core mode.
+    pub fn build(&self) -> io::Result<Runtime> {
+        let mut builder = Builder::new_multi_thread();
+        builder.enable_all().worker_threads(self.workers());
+        if let Some(max) = self.max_blocking_threads {
+            builder.max_blocking_threads(max);
+        }
+        builder.build()
+    }
+
+    fn build_shard(&self) -> io::Result<Runtime> {
+        let mut builder = Builder::new_current_thread();
+        builder.enable_all();
+        if let Some(max) = self.max_blocking_threads {
+            builder.max_blocking_threads(max);
+        }
+        builder.build()
+    }
+}
+
+/// Runs a TCP server configured by `config` until it is shut down, on the runtime or
+/// shards `config.runtime` asks for.
+pub fn run_tcp<H: TcpHandler>(config: &ServerConfig, handler: H) -> Result<(), Box<dyn Error>> {
+    if !config.runtime.per_core {
+        return config
+            .runtime
+            .build()?
+            .block_on(crate::run_tcp_with_config(config, handler));
+    }
+    let handler = Sharded::new(handler);
+    run_sharded(config, |builder| builder.tcp(handler.clone()))
+}
+
+/// Runs a UDP server like [`run_tcp`]. Per-core shards each get their own socket;
+/// the kernel keeps every client on the same one.
+pub fn run_udp<H: UdpHandler>(config: &ServerConfig, handler: H) -> Result<(), Box<dyn Error>> {
+    if !config.runtime.per_core {
+        return config
+            .runtime
+            .build()?
+            .block_on(crate::run_udp_with_config(config, handler));
+    }
+    let handler = Sharded::new(handler);
+    run_sharded(config, |builder| builder.udp(handler.clone()))
+}
+
+fn run_sharded<S, Fut>(config: &ServerConfig, start: S) -> Result<(), Box<dyn Error>>
+where
+    S: Fn(ServerBuilder) -> Fut + Sync,
+    Fut: Future<Output = io::Result<ServerHandle>>,
+{
+    config.apply_logging();
+    let shared = Shards {
+        start,
+        shutdown: Shutdown::new(),
+        metrics: Metrics::new(),
+        connections: Connections::new(),
+    };
+    let shards = config.runtime.workers();
+
+    let results = thread::scope(|scope| {
+        // The first shard binds alone so the rest can join it on the ports it got,
+        // ephemeral ones included
+        let (bound_tx, bound_rx) = mpsc::channel();
+        let mut threads = vec![scope.spawn(|| shared.run(0, config.clone(), Some(bound_tx)))];
+
+        if let Ok(addrs) = bound_rx.recv() {
+            if addrs.is_empty() && shards > 1 {
+                log_warning!(
+                    config.addr,
+                    "Per-core mode needs an IP address to share, serving on one shard"
+                );
+            } else {
+                for index in 1..shards {
+                    let mut config = config.clone();
+                    config.addr.clone_from(&addrs);
+                    config.metrics_addr = None;
+                    config.admin_addr = None;
+                    let shared = &shared;
+                    threads.push(scope.spawn(move || shared.run(index, config, None)));
+                }
+            }
+        }
+
+        threads
+            .into_iter()
+            .map(|thread| {
+                thread
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("shard panicked")))
+            })
+            .collect::<Vec<_>>()
+    });
+
+    shared.metrics.print_stats();
+    results.into_iter().collect::<io::Result<()>>()?;
+    Ok(())
+}
+
+/// What the shards of one server share.
+struct Shards<S> {
+    start: S,
+    shutdown: Shutdown,
+    metrics: Metrics,
+    connections: Connections,
+}
+
+impl<S, Fut> Shards<S>
+where
+    S: Fn(ServerBuilder) -> Fut,
+    Fut: Future<Output = io::Result<ServerHandle>>,
+{
+    /// Runs one shard to completion on its own runtime. The first reports the IP
+    /// addresses it listens on through `bound`.
+    fn run(
+        &self,
+        index: usize,
+        config: ServerConfig,
+        bound: Option<mpsc::Sender<String>>,
+    ) -> io::Result<()> {
+        let runtime = config.runtime.build_shard()?;
+        let result = runtime.block_on(async {
+            let mut builder = ServerBuilder::from_config(&config)
+                .shutdown(self.shutdown.clone())
+                .metrics(self.metrics.clone())
+                .connections(self.connections.clone())
+                .shard(index);
+            if index == 0 {
+                self.shutdown.trigger_on_signals();
+                builder = builder.stats_interval(config.stats_interval);
+            }
+
+            let server = (self.start)(builder).await?;
+            if let Some(bound) = bound {
+                let addrs: Vec<String> = server
+                    .local_addrs()
+                    .iter()
+                    .filter_map(|addr| addr.as_inet())
+                    .map(|addr| addr.to_string())
+                    .collect();
+                let _ = bound.send(addrs.join(","));
+            }
+            server.wait().await
+        });
+
+        // One shard failing takes the others down with it
+        if result.is_err() {
+            self.shutdown.trigger();
+        }
+        result
+    }
+}
+
+/// A handler shared by every shard, shut down once the last of them stops. Each shard
+/// serves its own clone; a clone dropped unused, by a shard that failed to start,
+/// stops counting as running.
+struct Sharded<H> {
+    inner: Arc<H>,
+    running: Arc<AtomicUsize>,
+    /// Whether this clone still counts in `running`.
+    counted: AtomicBool,
+}
+
+impl<H> Sharded<H> {
+    fn new(handler: H) -> Self {
+        Self {
+            inner: Arc::new(handler),
+            running: Arc::new(AtomicUsize::new(0)),
+            counted: AtomicBool::new(false),
+        }
+    }
+
+    /// Stops counting this clone, returning whether it was the last one running.
+    fn stop(&self) -> bool {
+        self.counted.swap(false, Ordering::AcqRel)
+            && self.running.fetch_sub(1, Ordering::AcqRel) == 1
+    }
+}
+
+impl<H> Clone for Sharded<H> {
+    fn clone(&self) -> Self {
+        self.running.fetch_add(1, Ordering::AcqRel);
+        Self {
+            inner: self.inner.clone(),
+            running: self.running.clone(),
+            counted: AtomicBool::new(true),
+        }
+    }
+}
+
+impl<H> Drop for Sharded<H> {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl<H: TcpHandler> TcpHandler for Sharded<H> {
+    fn handle(
+        &self,
+        stream: Stream,
+        context: ConnectionContext,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        self.inner.handle(stream, context)
+    }
+
+    async fn shutdown(&self) {
+        if self.stop() {
+            self.inner.shutdown().await;
+        }
+    }
+}
+
+impl<H: UdpHandler> UdpHandler for Sharded<H> {
+    fn handle(
+        &self,
+        packet: Datagram,
+        socket: DatagramSocket,
+        context: ConnectionContext,
+    ) -> impl Future<Output = Result<(), HandlerError>> + Send {
+        self.inner.handle(packet, socket, context)
+    }
+
+    async fn shutdown(&self) {
+        if self.stop() {
+            self.inner.shutdown().await;
+        }
+    }
+}
@@ -0,0 +1,127 @@
+use std::{sync::Arc, time::Duration};
+use tokio::{sync::watch, task::JoinSet};
+
+#[cfg(unix)]
+use crate::{activation, log_error, log_info};
+
+pub(crate) const DEFAULT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Cloneable handle used to stop a running server and let its handlers drain.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    grace_period: Duration,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self {
+            tx: Arc::new(tx),
+            grace_period: DEFAULT_GRACE_PERIOD,
+        }
+    }
+
+    /// Creates a handle that is triggered by SIGINT or SIGTERM, or by SIGUSR2 once a
+    /// replacement process has taken over the listening sockets.
+    pub fn on_signals() -> Self {
+        let shutdown = Self::new();
+        shutdown.trigger_on_signals();
+        shutdown
+    }
+
+    /// Triggers this handle on the signals [`on_signals`](Self::on_signals) listens
+    /// for, from a task on the current runtime.
+    pub fn trigger_on_signals(&self) {
+        let trigger = self.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            trigger.trigger();
+        });
+    }
+
+    pub fn with_grace_period(mut self, grace_period: Duration) -> Self {
+        self.grace_period = grace_period;
+        self
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        self.grace_period
+    }
+
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        let _ = rx.wait_for(|&triggered| triggered).await;
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Waits for all tasks to finish within the grace period, then aborts the rest.
+/// Returns the number of tasks that had to be aborted.
+pub(crate) async fn drain(tasks: &mut JoinSet<()>, grace_period: Duration) -> usize {
+    let finished = tokio::time::timeout(grace_period, async {
+        while tasks.join_next().await.is_some() {}
+    })
+    .await;
+
+    if finished.is_ok() {
+        return 0;
+    }
+
+    let remaining = tasks.len();
+    tasks.shutdown().await;
+    remaining
+}
+
+async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let (Ok(mut sigterm), Ok(mut sigusr2)) = (
+            signal(SignalKind::terminate()),
+            signal(SignalKind::user_defined2()),
+        ) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => return,
+                _ = sigterm.recv() => return,
+                _ = sigusr2.recv() => tokio::select! {
+                    _ = tokio::signal::ctrl_c() => return,
+                    _ = sigterm.recv() => return,
+                    result = activation::spawn_replacement() => match result {
+                        Ok(pid) => {
+                            log_info!("upgrade", format!("Replacement process {} ready, draining", pid));
+                            return;
+                        }
+                        Err(e) => log_error!(
+                            "upgrade",
+                            format!("Couldn't hand over to a replacement, still serving: {}", e)
+                        ),
+                    },
+                },
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
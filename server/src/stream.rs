@@ -0,0 +1,437 @@
+use std::{
+    future::{Future, poll_fn},
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf, ReadHalf, WriteHalf},
+    net::{TcpStream, UnixStream},
+    time::{Sleep, sleep},
+};
+use tokio_rustls::{TlsAcceptor, server::TlsStream};
+use tokio_util::sync::{CancellationToken, WaitForCancellationFutureOwned};
+
+use crate::{
+    Address, ConnectionContext, Metrics,
+    capture::{Direction, Recorder},
+    faults::{Abort, Faults, FaultyStream},
+    listener::Incoming,
+    log_warning,
+};
+
+/// Read deadlines enforced on every stream handed to a TCP handler.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    /// Longest a read may wait for data once the client has sent something.
+    pub idle: Option<Duration>,
+    /// Longest a read may wait for the client's first bytes.
+    pub handshake: Option<Duration>,
+    /// Longest a connection may stay open, regardless of activity. Reads and writes
+    /// fail once it passes; handlers still running after a further shutdown grace
+    /// period are dropped.
+    pub lifetime: Option<Duration>,
+}
+
+/// A client connection as seen by a TCP handler, accepted over TCP or a Unix domain
+/// socket.
+pub struct Stream {
+    inner: Transport,
+    metrics: Metrics,
+    timeouts: Timeouts,
+    received_any: bool,
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    expires: Option<Pin<Box<Sleep>>>,
+    expired: bool,
+    capture: Option<Recorder>,
+    context: Option<ConnectionContext>,
+    kick: Option<(CancellationToken, Pin<Box<WaitForCancellationFutureOwned>>)>,
+}
+
+enum Transport {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+    // Shared with the server, which sends any writes still held once the handler is done
+    Faulty(Arc<Mutex<FaultyStream<Transport>>>),
+}
+
+/// Writes a [`Stream`] has acknowledged but is still holding back to inject faults.
+pub(crate) struct HeldWrites(Arc<Mutex<FaultyStream<Transport>>>);
+
+impl Stream {
+    /// Wraps an accepted connection, completing the TLS handshake first when `tls` is set.
+    /// The handshake must finish within the handshake (or idle) timeout. Unix domain
+    /// socket connections are local and never use TLS.
+    pub(crate) async fn accept(
+        incoming: Incoming,
+        tls: Option<&TlsAcceptor>,
+        faults: &Faults,
+        metrics: Metrics,
+        timeouts: Timeouts,
+    ) -> io::Result<Self> {
+        let mut inner = match incoming {
+            Incoming::Unix(inner) => Transport::Unix(inner),
+            Incoming::Tcp(inner) => match tls {
+                Some(acceptor) => {
+                    Transport::Tls(Box::new(handshake(acceptor, inner, timeouts).await?))
+                }
+                None => Transport::Plain(inner),
+            },
+        };
+        // Above TLS, so handlers see the faults rather than the TLS layer
+        if faults.is_tcp_enabled() {
+            let faulty = FaultyStream::new(inner, *faults, metrics.clone());
+            inner = Transport::Faulty(Arc::new(Mutex::new(faulty)));
+        }
+
+        Ok(Self::new(inner, metrics, timeouts))
+    }
+
+    fn new(inner: Transport, metrics: Metrics, timeouts: Timeouts) -> Self {
+        Self {
+            inner,
+            metrics,
+            timeouts,
+            received_any: false,
+            read_deadline: None,
+            expires: timeouts.lifetime.map(|lifetime| Box::pin(sleep(lifetime))),
+            expired: false,
+            capture: None,
+            context: None,
+            kick: None,
+        }
+    }
+
+    /// Counts bytes into `context` and fails reads and writes once `kick` is cancelled.
+    pub(crate) fn track(&mut self, context: ConnectionContext, kick: CancellationToken) {
+        self.context = Some(context);
+        let cancelled = Box::pin(kick.clone().cancelled_owned());
+        self.kick = Some((kick, cancelled));
+    }
+
+    /// A handle for sending what the handler wrote but fault injection is holding back,
+    /// which would otherwise be lost when the handler drops the stream.
+    pub(crate) fn held_writes(&self) -> Option<HeldWrites> {
+        match &self.inner {
+            Transport::Faulty(stream) => Some(HeldWrites(stream.clone())),
+            _ => None,
+        }
+    }
+
+    /// Records everything read and written from now on.
+    pub(crate) fn capture_to(&mut self, recorder: Recorder) {
+        self.capture = Some(recorder);
+    }
+
+    pub fn peer_addr(&self) -> io::Result<Address> {
+        self.inner.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<Address> {
+        self.inner.local_addr()
+    }
+
+    pub fn is_tls(&self) -> bool {
+        self.inner.is_tls()
+    }
+
+    pub fn into_split(self) -> (ReadHalf<Self>, WriteHalf<Self>) {
+        tokio::io::split(self)
+    }
+
+    /// Fails once the session lifetime has passed or the connection was kicked, so
+    /// handlers see an error and clean up.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> io::Result<()> {
+        if let Some((token, cancelled)) = self.kick.as_mut()
+            && (token.is_cancelled() || cancelled.as_mut().poll(cx).is_ready())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "kicked by admin",
+            ));
+        }
+
+        if !self.expired {
+            let Some(expires) = self.expires.as_mut() else {
+                return Ok(());
+            };
+            if expires.as_mut().poll(cx).is_pending() {
+                return Ok(());
+            }
+            self.expired = true;
+            self.metrics.timeout_occurred();
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "session lifetime exceeded",
+        ))
+    }
+
+    /// A failing capture is given up rather than failing the connection.
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        let Some(recorder) = self.capture.as_mut() else {
+            return;
+        };
+        if let Err(e) = recorder.record(direction, data) {
+            log_warning!(recorder.peer(), format!("Traffic capture stopped: {}", e));
+            self.capture = None;
+        }
+    }
+
+    fn count(&self, count: impl Fn(&ConnectionContext, u64), bytes: usize) {
+        if let Some(context) = &self.context {
+            count(context, bytes as u64);
+        }
+    }
+
+    fn read_timeout(&self) -> Option<(Duration, &'static str)> {
+        if self.received_any {
+            self.timeouts.idle.map(|t| (t, "idle timeout"))
+        } else {
+            self.timeouts
+                .handshake
+                .map(|t| (t, "handshake timeout"))
+                .or(self.timeouts.idle.map(|t| (t, "idle timeout")))
+        }
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.poll_expired(cx)?;
+        let before = buf.filled().len();
+
+        if let Poll::Ready(result) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            if buf.filled().len() > before {
+                this.received_any = true;
+                this.count(
+                    ConnectionContext::bytes_received,
+                    buf.filled().len() - before,
+                );
+                this.record(Direction::Received, &buf.filled()[before..]);
+            } else if result.is_ok() && buf.remaining() > 0 {
+                // End of stream, recorded as an empty read
+                this.record(Direction::Received, &[]);
+            }
+            this.read_deadline = None;
+            return Poll::Ready(result);
+        }
+
+        let Some((timeout, reason)) = this.read_timeout() else {
+            return Poll::Pending;
+        };
+
+        // The deadline starts with the first pending read and survives until data arrives
+        let deadline = this
+            .read_deadline
+            .get_or_insert_with(|| Box::pin(sleep(timeout)));
+        if deadline.as_mut().poll(cx).is_ready() {
+            this.read_deadline = None;
+            this.metrics.timeout_occurred();
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::TimedOut, reason)));
+        }
+
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_expired(cx)?;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.count(ConnectionContext::bytes_sent, written);
+            this.record(Direction::Sent, &buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.poll_expired(cx)?;
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        if let Poll::Ready(Ok(mut written)) = poll {
+            this.count(ConnectionContext::bytes_sent, written);
+            for buf in bufs {
+                if written == 0 {
+                    break;
+                }
+                let len = buf.len().min(written);
+                this.record(Direction::Sent, &buf[..len]);
+                written -= len;
+            }
+        }
+        poll
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl HeldWrites {
+    pub(crate) async fn send(self) -> io::Result<()> {
+        poll_fn(|cx| self.0.lock().unwrap().poll_send_held(cx)).await
+    }
+}
+
+impl Transport {
+    fn peer_addr(&self) -> io::Result<Address> {
+        match self {
+            Self::Plain(stream) => stream.peer_addr().map(Address::Inet),
+            Self::Tls(stream) => stream.get_ref().0.peer_addr().map(Address::Inet),
+            Self::Unix(stream) => stream.peer_addr().map(Address::from_unix),
+            Self::Faulty(stream) => stream.lock().unwrap().get_ref().peer_addr(),
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<Address> {
+        match self {
+            Self::Plain(stream) => stream.local_addr().map(Address::Inet),
+            Self::Tls(stream) => stream.get_ref().0.local_addr().map(Address::Inet),
+            Self::Unix(stream) => stream.local_addr().map(Address::from_unix),
+            Self::Faulty(stream) => stream.lock().unwrap().get_ref().local_addr(),
+        }
+    }
+
+    fn is_tls(&self) -> bool {
+        match self {
+            Self::Tls(_) => true,
+            Self::Faulty(stream) => stream.lock().unwrap().get_ref().is_tls(),
+            _ => false,
+        }
+    }
+}
+
+impl Abort for Transport {
+    /// Closing with a zero linger time sends a TCP reset.
+    fn abort(&self) {
+        let _ = match self {
+            Self::Plain(stream) => stream.set_zero_linger(),
+            Self::Tls(stream) => stream.get_ref().0.set_zero_linger(),
+            Self::Unix(_) => Ok(()),
+            Self::Faulty(stream) => {
+                stream.lock().unwrap().get_ref().abort();
+                Ok(())
+            }
+        };
+    }
+}
+
+impl AsyncRead for Transport {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Faulty(stream) => Pin::new(&mut *stream.lock().unwrap()).poll_read(cx, buf),
+            // Most clients hang up without close_notify, which is a plain EOF to handlers
+            Self::Tls(stream) => match Pin::new(stream).poll_read(cx, buf) {
+                Poll::Ready(Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    Poll::Ready(Ok(()))
+                }
+                poll => poll,
+            },
+        }
+    }
+}
+
+impl AsyncWrite for Transport {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Faulty(stream) => Pin::new(&mut *stream.lock().unwrap()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Tls(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Self::Faulty(stream) => {
+                Pin::new(&mut *stream.lock().unwrap()).poll_write_vectored(cx, bufs)
+            }
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Self::Plain(stream) => stream.is_write_vectored(),
+            Self::Tls(stream) => stream.is_write_vectored(),
+            Self::Unix(stream) => stream.is_write_vectored(),
+            Self::Faulty(stream) => stream.lock().unwrap().is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Faulty(stream) => Pin::new(&mut *stream.lock().unwrap()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Faulty(stream) => Pin::new(&mut *stream.lock().unwrap()).poll_shutdown(cx),
+        }
+    }
+}
+
+async fn handshake(
+    acceptor: &TlsAcceptor,
+    inner: TcpStream,
+    timeouts: Timeouts,
+) -> io::Result<TlsStream<TcpStream>> {
+    let handshake = acceptor.accept(inner);
+    match timeouts.handshake.or(timeouts.idle) {
+        Some(timeout) => tokio::time::timeout(timeout, handshake)
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timeout"))?,
+        None => handshake.await,
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use server::{ConnectionContext, HandlerError, Metrics, ServerBuilder, Stream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{Instant, sleep, timeout},
+};
+
+async fn echo(mut stream: Stream, _ctx: ConnectionContext) -> Result<(), HandlerError> {
+    let mut buf = [0; 1024];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        stream.write_all(&buf[..n]).await?;
+    }
+}
+
+/// Says goodbye once the server starts shutting down.
+async fn farewell(mut stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+    ctx.cancelled().await;
+    stream.write_all(b"bye\n").await?;
+    Ok(())
+}
+
+fn load(counter: &AtomicU64) -> u64 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Waits for `counter` to reach `value`, which handlers update after the client sees
+/// their last bytes.
+async fn settle(counter: &AtomicU64, value: u64) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while load(counter) != value {
+        assert!(Instant::now() < deadline, "stuck at {}", load(counter));
+        sleep(Duration::from_millis(10)).await;
+    }
+}
+
+async fn read_all(stream: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    timeout(Duration::from_secs(5), stream.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    received
+}
+
+#[tokio::test]
+async fn serves_every_listener_and_counts_traffic() {
+    let server = ServerBuilder::new("127.0.0.1:0,127.0.0.1:0")
+        .tcp(echo)
+        .await
+        .unwrap();
+    let addrs: Vec<_> = server
+        .local_addrs()
+        .iter()
+        .map(|addr| addr.as_inet().unwrap())
+        .collect();
+    assert_eq!(addrs.len(), 2);
+    assert_ne!(addrs[0].port(), addrs[1].port());
+    assert_eq!(server.local_addr().as_inet(), Some(addrs[0]));
+
+    for addr in &addrs {
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"hello").await.unwrap();
+        client.shutdown().await.unwrap();
+        assert_eq!(read_all(&mut client).await, b"hello");
+    }
+
+    let metrics: &Metrics = server.metrics();
+    settle(&metrics.connections_active, 0).await;
+    assert_eq!(load(&metrics.connections_total), 2);
+    assert_eq!(load(&metrics.bytes_received), 10);
+    assert_eq!(load(&metrics.bytes_sent), 10);
+    assert_eq!(load(&metrics.errors_total), 0);
+    assert_eq!(metrics.connection_duration.snapshot().count, 2);
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_drains_connections_in_flight() {
+    let server = ServerBuilder::new("127.0.0.1:0")
+        .grace_period(Duration::from_secs(5))
+        .tcp(farewell)
+        .await
+        .unwrap();
+    let addr = server.local_addr().as_inet().unwrap();
+    let metrics = server.metrics().clone();
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    settle(&metrics.connections_active, 1).await;
+
+    let started = Instant::now();
+    server.shutdown().await.unwrap();
+    assert!(started.elapsed() < Duration::from_secs(5));
+    assert_eq!(read_all(&mut client).await, b"bye\n");
+    assert_eq!(load(&metrics.connections_active), 0);
+    assert_eq!(load(&metrics.bytes_sent), 4);
+
+    // Nothing listens any more
+    assert!(TcpStream::connect(addr).await.is_err());
+}
+
+#[tokio::test]
+async fn shutdown_aborts_handlers_after_the_grace_period() {
+    let server = ServerBuilder::new("127.0.0.1:0")
+        .grace_period(Duration::from_millis(100))
+        .tcp(echo)
+        .await
+        .unwrap();
+    let addr = server.local_addr().as_inet().unwrap();
+    let metrics = server.metrics().clone();
+
+    // The echo handler ignores shutdown and waits for a client that never writes
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    settle(&metrics.connections_active, 1).await;
+
+    timeout(Duration::from_secs(5), server.shutdown())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(read_all(&mut client).await, b"");
+}
@@ -0,0 +1,102 @@
+use std::{io, sync::atomic::Ordering, time::Duration};
+
+use server::{AdmissionLimits, ConnectionContext, HandlerError, ServerBuilder, Stream};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+const HEADER: &[u8] = b"PROXY TCP4 192.0.2.1 127.0.0.1 1000 80\r\n";
+
+async fn greet(mut stream: Stream, ctx: ConnectionContext) -> Result<(), HandlerError> {
+    stream
+        .write_all(format!("{}\n", ctx.peer()).as_bytes())
+        .await?;
+    Ok(())
+}
+
+/// Reads until the server closes the connection. A server closing without reading
+/// everything resets it, which counts as closing here.
+async fn read_all(stream: &mut TcpStream) -> Vec<u8> {
+    let mut received = Vec::new();
+    let read = timeout(Duration::from_secs(10), stream.read_to_end(&mut received))
+        .await
+        .unwrap();
+    if let Err(e) = read {
+        assert_eq!(e.kind(), io::ErrorKind::ConnectionReset);
+    }
+    received
+}
+
+#[tokio::test]
+async fn silent_balancer_holds_a_slot_until_the_header_times_out() {
+    // No handshake or idle timeout, so only the default limit on the header applies
+    let server = ServerBuilder::new("127.0.0.1:0")
+        .proxy_protocol(vec!["127.0.0.1".parse().unwrap()])
+        .admission(AdmissionLimits {
+            max_connections: Some(1),
+            ..Default::default()
+        })
+        .tcp(greet)
+        .await
+        .unwrap();
+    let addr = server.local_addr().as_inet().unwrap();
+    let metrics = server.metrics().clone();
+
+    let mut silent = TcpStream::connect(addr).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let mut refused = TcpStream::connect(addr).await.unwrap();
+    refused.write_all(HEADER).await.unwrap();
+    assert_eq!(read_all(&mut refused).await, b"");
+    assert_eq!(metrics.connections_rejected.load(Ordering::Relaxed), 1);
+
+    assert_eq!(read_all(&mut silent).await, b"");
+    assert_eq!(metrics.proxy_header_failures.load(Ordering::Relaxed), 1);
+
+    let mut proxied = TcpStream::connect(addr).await.unwrap();
+    proxied.write_all(HEADER).await.unwrap();
+    assert_eq!(read_all(&mut proxied).await, b"192.0.2.1:1000\n");
+
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn per_ip_limits_apply_to_the_client_named_in_the_header() {
+    let server = ServerBuilder::new("127.0.0.1:0")
+        .proxy_protocol(vec!["127.0.0.0/8".parse().unwrap()])
+        .admission(AdmissionLimits {
+            max_connections_per_ip: Some(1),
+            ..Default::default()
+        })
+        .tcp(|stream: Stream, _ctx: ConnectionContext| async move {
+            // Holds the connection until the client leaves
+            let mut stream = stream;
+            let mut buf = [0; 1];
+            while stream.read(&mut buf).await? > 0 {}
+            Ok(())
+        })
+        .await
+        .unwrap();
+    let addr = server.local_addr().as_inet().unwrap();
+    let metrics = server.metrics().clone();
+
+    let mut first = TcpStream::connect(addr).await.unwrap();
+    first.write_all(HEADER).await.unwrap();
+    let mut other = TcpStream::connect(addr).await.unwrap();
+    other
+        .write_all(b"PROXY TCP4 192.0.2.2 127.0.0.1 1000 80\r\n")
+        .await
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // Same balancer address, but a client already connected
+    let mut same = TcpStream::connect(addr).await.unwrap();
+    same.write_all(HEADER).await.unwrap();
+    assert_eq!(read_all(&mut same).await, b"");
+    assert_eq!(metrics.connections_rejected.load(Ordering::Relaxed), 1);
+    assert_eq!(metrics.connections_active.load(Ordering::Relaxed), 2);
+
+    drop((first, other));
+    server.shutdown().await.unwrap();
+}